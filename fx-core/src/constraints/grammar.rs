@@ -0,0 +1,566 @@
+//! GBNF grammars, in the format used by llama.cpp. A grammar is matched by keeping every possible
+//! stack of rule positions, which is advanced one character at a time.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use super::ConstraintError;
+
+#[derive(Clone, Debug)]
+enum Element {
+    /// One character in (or, if `negated`, not in) any of the inclusive ranges.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+type Alternative = Vec<Element>;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+/// Every stack has a `Chars` element at its top position, except an empty stack, which means the
+/// input so far is a complete match. `partial` holds the bytes of an incomplete character.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct GrammarState {
+    stacks: Vec<Vec<Pos>>,
+    partial: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Grammar {
+    rules: Vec<Vec<Alternative>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub(crate) fn parse(src: &str) -> Result<Self> {
+        let mut parser = Parser {
+            src: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: Vec::new(),
+        };
+        parser.parse()?;
+
+        let mut rules = Vec::new();
+        for (i, rule) in parser.rules.into_iter().enumerate() {
+            let Some(rule) = rule else {
+                let name = parser
+                    .names
+                    .iter()
+                    .find(|(_, id)| **id == i)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_default();
+                return Err(ConstraintError::Grammar(format!("Undefined rule `{name}`.")).into());
+            };
+            rules.push(rule);
+        }
+        let root = *parser
+            .names
+            .get("root")
+            .ok_or_else(|| ConstraintError::Grammar("Missing `root` rule.".to_string()))?;
+
+        let grammar = Self { rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// Left recursive rules would have to be expanded forever.
+    fn check_left_recursion(&self) -> Result<()> {
+        let mut nullable = vec![false; self.rules.len()];
+        loop {
+            let mut changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alts.iter().any(|alt| {
+                        alt.iter()
+                            .all(|elem| matches!(elem, Element::Rule(r) if nullable[*r]))
+                    })
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Rules which may be expanded without consuming a character.
+        let leftmost = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut refs = Vec::new();
+                for alt in alts {
+                    for elem in alt {
+                        let Element::Rule(r) = elem else {
+                            break;
+                        };
+                        refs.push(*r);
+                        if !nullable[*r] {
+                            break;
+                        }
+                    }
+                }
+                refs
+            })
+            .collect::<Vec<_>>();
+        for start in 0..self.rules.len() {
+            let mut seen = vec![false; self.rules.len()];
+            let mut todo = leftmost[start].clone();
+            while let Some(rule) = todo.pop() {
+                if rule == start {
+                    return Err(ConstraintError::Grammar(
+                        "Left recursive rules are not supported.".to_string(),
+                    )
+                    .into());
+                }
+                if !seen[rule] {
+                    seen[rule] = true;
+                    todo.extend(&leftmost[rule]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn start(&self) -> GrammarState {
+        let mut stacks = Vec::new();
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(
+                vec![Pos {
+                    rule: self.root,
+                    alt,
+                    elem: 0,
+                }],
+                &mut stacks,
+            );
+        }
+        normalize(&mut stacks);
+        GrammarState {
+            stacks,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Push the stacks reachable from `stack` without consuming a character.
+    fn expand(&self, mut stack: Vec<Pos>, out: &mut Vec<Vec<Pos>>) {
+        let Some(top) = stack.last().copied() else {
+            out.push(stack);
+            return;
+        };
+        let alt = &self.rules[top.rule][top.alt];
+        match alt.get(top.elem) {
+            None => {
+                stack.pop();
+                if let Some(parent) = stack.last_mut() {
+                    parent.elem += 1;
+                }
+                self.expand(stack, out);
+            }
+            Some(Element::Chars { .. }) => out.push(stack),
+            Some(Element::Rule(rule)) => {
+                // A reference at the end of an alternative is a tail call: the caller is complete
+                // once the callee is, so its position is dropped rather than kept below the callee.
+                // This keeps the stacks of recursive rules such as repetitions bounded.
+                if top.elem + 1 == alt.len() {
+                    stack.pop();
+                }
+                for alt in 0..self.rules[*rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(Pos {
+                        rule: *rule,
+                        alt,
+                        elem: 0,
+                    });
+                    self.expand(stack, out);
+                }
+            }
+        }
+    }
+
+    fn step_char(&self, stacks: &[Vec<Pos>], c: char) -> Vec<Vec<Pos>> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(top) = stack.last() else {
+                continue;
+            };
+            if self.rules[top.rule][top.alt][top.elem].matches(c) {
+                let mut stack = stack.clone();
+                // NOTE Unwrap reasoning: The stack is not empty.
+                stack.last_mut().unwrap().elem += 1;
+                self.expand(stack, &mut out);
+            }
+        }
+        normalize(&mut out);
+        out
+    }
+
+    /// The state after `bytes`, or `None` if no match can start with them.
+    pub(crate) fn step(&self, state: &GrammarState, bytes: &[u8]) -> Option<GrammarState> {
+        let mut input = state.partial.clone();
+        input.extend_from_slice(bytes);
+        let (valid, partial) = match std::str::from_utf8(&input) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                // Only an incomplete character may remain at the end.
+                if e.error_len().is_some() {
+                    return None;
+                }
+                let (valid, partial) = input.split_at(e.valid_up_to());
+                // NOTE Unwrap reasoning: This prefix was just checked to be valid.
+                (std::str::from_utf8(valid).unwrap(), partial)
+            }
+        };
+
+        let mut stacks = state.stacks.clone();
+        for c in valid.chars() {
+            stacks = self.step_char(&stacks, c);
+            if stacks.is_empty() {
+                return None;
+            }
+        }
+        if !partial.is_empty() && stacks.iter().all(|stack| stack.is_empty()) {
+            return None;
+        }
+        Some(GrammarState {
+            stacks,
+            partial: partial.to_vec(),
+        })
+    }
+
+    pub(crate) fn is_accepting(&self, state: &GrammarState) -> bool {
+        state.partial.is_empty() && state.stacks.iter().any(|stack| stack.is_empty())
+    }
+}
+
+fn normalize(stacks: &mut Vec<Vec<Pos>>) {
+    stacks.sort_unstable();
+    stacks.dedup();
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    /// `None` for rules which have been referenced but not defined yet.
+    rules: Vec<Option<Vec<Alternative>>>,
+}
+
+impl Parser {
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        Err(ConstraintError::Grammar(format!("{msg} (at character {})", self.pos)).into())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let Some(c) = self.peek() else {
+            return self.error("Unexpected end of grammar");
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                return self.error(&format!("Expected `{s}`"));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Skip whitespace and comments, and newlines if `newline_ok`.
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\n' | '\r' if !newline_ok => return,
+                c if c.is_whitespace() => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.names.get(name) {
+            return *id;
+        }
+        self.rules.push(None);
+        let id = self.rules.len() - 1;
+        self.names.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, alts: Vec<Alternative>) -> usize {
+        self.rules.push(Some(alts));
+        self.rules.len() - 1
+    }
+
+    fn parse(&mut self) -> Result<()> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alts = self.parse_alternatives(false)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return self.error(&format!("Rule `{name}` is defined twice"));
+            }
+            self.rules[id] = Some(alts);
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("Expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_alternatives(&mut self, nested: bool) -> Result<Vec<Alternative>> {
+        let mut alts = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Alternative> {
+        let mut seq = Vec::new();
+        // Start of the last symbol, which a repetition operator applies to.
+        let mut last_start = 0;
+        loop {
+            self.skip_space(nested);
+            let Some(c) = self.peek() else {
+                return Ok(seq);
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        seq.push(Element::Chars {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(self.parse_class()?);
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(Element::Chars {
+                        ranges: Vec::new(),
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternatives(true)?;
+                    self.expect(")")?;
+                    last_start = seq.len();
+                    seq.push(Element::Rule(self.new_rule(alts)));
+                }
+                '*' | '+' | '?' => {
+                    self.pos += 1;
+                    if last_start == seq.len() {
+                        return self.error("Expected a symbol before the repetition operator");
+                    }
+                    let symbol = seq.split_off(last_start);
+                    seq.push(Element::Rule(self.repeat(symbol, c)));
+                }
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    last_start = seq.len();
+                    seq.push(Element::Rule(self.rule_id(&name)));
+                }
+                _ => return Ok(seq),
+            }
+        }
+    }
+
+    /// A new rule for `symbol` repeated as given by `op`.
+    fn repeat(&mut self, symbol: Vec<Element>, op: char) -> usize {
+        match op {
+            '?' => self.new_rule(vec![symbol, Vec::new()]),
+            _ => {
+                // `symbol*` is `rule ::= symbol rule | ""`, `symbol+` is `symbol symbol*`.
+                let rule = self.new_rule(Vec::new());
+                let mut recurse = symbol.clone();
+                recurse.push(Element::Rule(rule));
+                self.rules[rule] = Some(vec![recurse, Vec::new()]);
+                if op == '*' {
+                    return rule;
+                }
+                let mut plus = symbol;
+                plus.push(Element::Rule(rule));
+                self.new_rule(vec![plus])
+            }
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Element> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        while self.peek() != Some(']') {
+            let lo = self.parse_char()?;
+            let hi = if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                self.parse_char()?
+            } else {
+                lo
+            };
+            ranges.push((lo, hi));
+        }
+        self.pos += 1;
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex_len = match self.next()? {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            c => return Ok(c),
+        };
+        let start = self.pos;
+        for _ in 0..hex_len {
+            self.next()?;
+        }
+        let hex = self.src[start..self.pos].iter().collect::<String>();
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("Invalid escape `{hex}`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grammar;
+
+    fn accepts(grammar: &Grammar, text: &str) -> bool {
+        grammar
+            .step(&grammar.start(), text.as_bytes())
+            .is_some_and(|state| grammar.is_accepting(&state))
+    }
+
+    #[test]
+    fn alternatives_and_repetitions() {
+        let grammar = Grammar::parse(
+            r#"
+            # A list of words.
+            root ::= word ("," " "? word)*
+            word ::= [a-z]+ | "\x22" [^"]* "\x22"
+            "#,
+        )
+        .unwrap();
+        assert!(accepts(&grammar, "foo"));
+        assert!(accepts(&grammar, "foo, bar,baz"));
+        assert!(accepts(&grammar, r#"foo, "Bar Baz""#));
+        assert!(!accepts(&grammar, "foo,"));
+        assert!(!accepts(&grammar, "Foo"));
+    }
+
+    #[test]
+    fn prefixes_are_not_accepting() {
+        let grammar = Grammar::parse(r#"root ::= "ab" "c"?"#).unwrap();
+        let state = grammar.step(&grammar.start(), b"a").unwrap();
+        assert!(!grammar.is_accepting(&state));
+        let state = grammar.step(&state, b"b").unwrap();
+        assert!(grammar.is_accepting(&state));
+        assert!(grammar.step(&state, b"b").is_none());
+    }
+
+    #[test]
+    fn chars_split_across_steps() {
+        let grammar = Grammar::parse(r#"root ::= [à-ÿ]"#).unwrap();
+        let bytes = "é".as_bytes();
+        let state = grammar.step(&grammar.start(), &bytes[..1]).unwrap();
+        assert!(!grammar.is_accepting(&state));
+        let state = grammar.step(&state, &bytes[1..]).unwrap();
+        assert!(grammar.is_accepting(&state));
+        assert!(grammar.step(&grammar.start(), "ä".as_bytes()).is_some());
+        assert!(grammar.step(&grammar.start(), "a".as_bytes()).is_none());
+    }
+
+    #[test]
+    fn repetition_stacks_stay_bounded() {
+        let grammar = Grammar::parse(r#"root ::= ("a" | "b")+ "c""#).unwrap();
+        let mut state = grammar.start();
+        for _ in 0..100 {
+            state = grammar.step(&state, b"ab").unwrap();
+            assert!(state.stacks.iter().all(|stack| stack.len() <= 3));
+        }
+        state = grammar.step(&state, b"c").unwrap();
+        assert!(grammar.is_accepting(&state));
+    }
+
+    #[test]
+    fn invalid_grammars_are_rejected() {
+        assert!(Grammar::parse(r#"item ::= "a""#).is_err());
+        assert!(Grammar::parse(r#"root ::= item"#).is_err());
+        assert!(Grammar::parse(r#"root ::= root "a" | "a""#).is_err());
+        assert!(Grammar::parse(r#"root ::= "a"#).is_err());
+    }
+}
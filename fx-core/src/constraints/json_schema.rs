@@ -0,0 +1,328 @@
+//! Conversion of JSON schemas into regexes matching the JSON values valid against them.
+
+use anyhow::Result;
+use regex_syntax::escape;
+use serde_json::{Map, Value};
+
+use super::ConstraintError;
+
+const WHITESPACE: &str = r"[ \t\n]*";
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(?:0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?";
+/// Regexes cannot be recursive, so references are only followed up to this depth.
+const MAX_REF_DEPTH: usize = 8;
+/// Arrays and objects in values of any type are only nested up to this depth.
+const ANY_VALUE_DEPTH: usize = 2;
+
+pub(crate) fn to_regex(schema: &Value) -> Result<String> {
+    Converter { root: schema }.convert(schema, 0)
+}
+
+fn unsupported<T>(msg: impl Into<String>) -> Result<T> {
+    Err(ConstraintError::JsonSchema(msg.into()).into())
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Converter<'a> {
+    fn convert(&self, schema: &'a Value, depth: usize) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(any_value(ANY_VALUE_DEPTH)),
+            Value::Object(schema) => schema,
+            _ => return unsupported(format!("Invalid schema `{schema}`.")),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            if depth == MAX_REF_DEPTH {
+                return unsupported("References are nested too deeply.");
+            }
+            return self.convert(self.resolve(reference)?, depth + 1);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(escape(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array() else {
+                return unsupported("`enum` must be an array.");
+            };
+            let values = values
+                .iter()
+                .map(|value| escape(&value.to_string()))
+                .collect::<Vec<_>>();
+            return Ok(format!("(?:{})", values.join("|")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let Some(schemas) = schemas.as_array() else {
+                return unsupported("`anyOf` and `oneOf` must be arrays.");
+            };
+            let alts = schemas
+                .iter()
+                .map(|schema| self.convert(schema, depth))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(format!("(?:{})", alts.join("|")));
+        }
+        if schema.contains_key("allOf") {
+            return unsupported("`allOf` is not supported.");
+        }
+
+        match schema.get("type") {
+            None => Ok(any_value(ANY_VALUE_DEPTH)),
+            Some(Value::String(ty)) => self.typed(ty, schema, depth),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|ty| match ty.as_str() {
+                        Some(ty) => self.typed(ty, schema, depth),
+                        None => unsupported(format!("Invalid type `{ty}`.")),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("(?:{})", alts.join("|")))
+            }
+            Some(ty) => unsupported(format!("Invalid type `{ty}`.")),
+        }
+    }
+
+    /// Resolve a local reference such as `#/$defs/item`.
+    fn resolve(&self, reference: &Value) -> Result<&'a Value> {
+        let Some(pointer) = reference.as_str().and_then(|r| r.strip_prefix('#')) else {
+            return unsupported(format!(
+                "Only local references are supported, got {reference}."
+            ));
+        };
+        match self.root.pointer(pointer) {
+            Some(schema) => Ok(schema),
+            None => unsupported(format!("Unresolved reference {reference}.")),
+        }
+    }
+
+    fn typed(&self, ty: &str, schema: &'a Map<String, Value>, depth: usize) -> Result<String> {
+        Ok(match ty {
+            "null" => "null".to_string(),
+            "boolean" => "(?:true|false)".to_string(),
+            "integer" => INTEGER.to_string(),
+            "number" => NUMBER.to_string(),
+            "string" => string(schema),
+            "array" => self.array(schema, depth)?,
+            "object" => self.object(schema, depth)?,
+            _ => return unsupported(format!("Unknown type `{ty}`.")),
+        })
+    }
+
+    fn array(&self, schema: &'a Map<String, Value>, depth: usize) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.convert(items, depth)?,
+            None => any_value(ANY_VALUE_DEPTH),
+        };
+        let min = usize_keyword(schema, "minItems").unwrap_or(0);
+        let max = usize_keyword(schema, "maxItems");
+        if max == Some(0) {
+            return Ok(format!(r"\[{WHITESPACE}\]"));
+        }
+        let rest = format!("{WHITESPACE},{WHITESPACE}{item}");
+        let rest = match max {
+            Some(max) => format!("(?:{rest}){{{},{}}}", min.saturating_sub(1), max - 1),
+            None => format!("(?:{rest}){{{},}}", min.saturating_sub(1)),
+        };
+        let items = if min == 0 {
+            format!("(?:{item}{rest})?")
+        } else {
+            format!("{item}{rest}")
+        };
+        Ok(format!(r"\[{WHITESPACE}{items}{WHITESPACE}\]"))
+    }
+
+    /// Properties are generated in the order of the schema, and only the required ones must be
+    /// present. No additional properties are generated.
+    fn object(&self, schema: &'a Map<String, Value>, depth: usize) -> Result<String> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok(any_object(ANY_VALUE_DEPTH));
+        };
+        let Some(properties) = properties.as_object() else {
+            return unsupported("`properties` must be an object.");
+        };
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| {
+                required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let member = format!(
+                "{}{WHITESPACE}:{WHITESPACE}{}",
+                escape(&Value::String(name.clone()).to_string()),
+                self.convert(property, depth)?
+            );
+            members.push((member, required.contains(&name.as_str())));
+        }
+
+        // Built back to front: `after` matches the remaining members if one was already generated,
+        // `first` if none was, in which case the next one has no leading comma.
+        let mut after = String::new();
+        let mut first = String::new();
+        for (member, required) in members.into_iter().rev() {
+            let with_comma = format!("{WHITESPACE},{WHITESPACE}{member}{after}");
+            let without_comma = format!("{member}{after}");
+            if required {
+                after = with_comma;
+                first = without_comma;
+            } else {
+                after = format!("(?:{WHITESPACE},{WHITESPACE}{member})?{after}");
+                first = format!("(?:{without_comma}|{first})");
+            }
+        }
+        Ok(format!(r"\{{{WHITESPACE}{first}{WHITESPACE}\}}"))
+    }
+}
+
+fn any_object(depth: usize) -> String {
+    let member = format!(
+        r#""{STRING_CHAR}*"{WHITESPACE}:{WHITESPACE}{}"#,
+        any_value(depth.saturating_sub(1))
+    );
+    format!(r"\{{{WHITESPACE}(?:{member}(?:{WHITESPACE},{WHITESPACE}{member})*)?{WHITESPACE}\}}")
+}
+
+/// Any JSON value, with arrays and objects nested up to `depth` levels.
+fn any_value(depth: usize) -> String {
+    let mut alts = vec![
+        "null".to_string(),
+        "true".to_string(),
+        "false".to_string(),
+        NUMBER.to_string(),
+        format!(r#""{STRING_CHAR}*""#),
+    ];
+    if depth > 0 {
+        let item = any_value(depth - 1);
+        alts.push(format!(
+            r"\[{WHITESPACE}(?:{item}(?:{WHITESPACE},{WHITESPACE}{item})*)?{WHITESPACE}\]"
+        ));
+        alts.push(any_object(depth));
+    }
+    format!("(?:{})", alts.join("|"))
+}
+
+/// A `pattern` may match anywhere in the string, as in JSON schema, unless it is anchored. It is
+/// spliced between the quotes as is, so it must not match an unescaped `"` itself.
+fn string(schema: &Map<String, Value>) -> String {
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+        let any = format!("{STRING_CHAR}*");
+        let (prefix, pattern) = match pattern.strip_prefix('^') {
+            Some(pattern) => ("", pattern),
+            None => (any.as_str(), pattern),
+        };
+        let (pattern, suffix) = match pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, ""),
+            None => (pattern, any.as_str()),
+        };
+        return format!(r#""{prefix}(?:{pattern}){suffix}""#);
+    }
+    let min = usize_keyword(schema, "minLength").unwrap_or(0);
+    match usize_keyword(schema, "maxLength") {
+        Some(max) => format!(r#""{STRING_CHAR}{{{min},{max}}}""#),
+        None => format!(r#""{STRING_CHAR}{{{min},}}""#),
+    }
+}
+
+fn usize_keyword(schema: &Map<String, Value>, keyword: &str) -> Option<usize> {
+    schema.get(keyword)?.as_u64().map(|n| n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_automata::meta::Regex;
+    use serde_json::{json, Value};
+
+    use super::to_regex;
+
+    fn regex(schema: Value) -> Regex {
+        Regex::new(&format!("^(?:{})$", to_regex(&schema).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn object_requires_only_required_properties() {
+        let regex = regex(json!({
+            "type": "object",
+            "properties": {
+                "age": { "type": "integer" },
+                "name": { "type": "string" },
+            },
+            "required": ["name"],
+        }));
+        assert!(regex.is_match(r#"{"name": "Ada"}"#));
+        assert!(regex.is_match(r#"{ "age": 36, "name": "Ada" }"#));
+        assert!(!regex.is_match(r#"{"age": 36}"#));
+        assert!(!regex.is_match(r#"{"name": 36}"#));
+        assert!(!regex.is_match(r#"{"name": "Ada", "email": "ada@example.com"}"#));
+    }
+
+    #[test]
+    fn object_properties_follow_the_schema_order() {
+        let regex = regex(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+            },
+            "required": ["name", "age"],
+        }));
+        assert!(regex.is_match(r#"{"name": "Ada", "age": 36}"#));
+        assert!(!regex.is_match(r#"{"age": 36, "name": "Ada"}"#));
+    }
+
+    #[test]
+    fn array_item_counts() {
+        let regex = regex(json!({
+            "type": "array",
+            "items": { "type": "number" },
+            "minItems": 1,
+            "maxItems": 2,
+        }));
+        assert!(regex.is_match("[1.5]"));
+        assert!(regex.is_match("[1, -2e3]"));
+        assert!(!regex.is_match("[]"));
+        assert!(!regex.is_match("[1, 2, 3]"));
+    }
+
+    #[test]
+    fn enums_consts_and_references() {
+        let regex = regex(json!({
+            "$defs": { "color": { "enum": ["red", "green"] } },
+            "anyOf": [{ "$ref": "#/$defs/color" }, { "const": null }],
+        }));
+        assert!(regex.is_match(r#""red""#));
+        assert!(regex.is_match("null"));
+        assert!(!regex.is_match(r#""blue""#));
+    }
+
+    #[test]
+    fn string_lengths_and_patterns() {
+        let regex = regex(json!({ "type": "string", "minLength": 2, "maxLength": 3 }));
+        assert!(regex.is_match(r#""ab""#));
+        assert!(!regex.is_match(r#""a""#));
+        assert!(!regex.is_match(r#""abcd""#));
+
+        let regex = regex(json!({ "type": "string", "pattern": "^[0-9]+$" }));
+        assert!(regex.is_match(r#""42""#));
+        assert!(!regex.is_match(r#""4a""#));
+
+        let regex = regex(json!({ "type": "string", "pattern": "[0-9]" }));
+        assert!(regex.is_match(r#""a4b""#));
+        assert!(!regex.is_match(r#""ab""#));
+    }
+
+    #[test]
+    fn unsupported_keywords_are_rejected() {
+        assert!(to_regex(&json!({ "allOf": [] })).is_err());
+        assert!(to_regex(&json!({ "$ref": "https://example.com/schema" })).is_err());
+        assert!(to_regex(&json!({ "type": "date" })).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//! Constrained decoding: the generated text is forced to match a regex, a JSON schema or a GBNF
+//! grammar. The constraint is compiled into an automaton over bytes, which is run over the bytes
+//! of every vocab token to find the tokens allowed in each state.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use regex_automata::{
+    dfa::{dense, Automaton as _, StartKind},
+    util::{primitives::StateID, start},
+    Anchored, MatchKind,
+};
+use thiserror::Error;
+use tokenizers::{decoders::DecoderWrapper, Tokenizer};
+
+use crate::get_mut_arcmutex;
+
+use self::grammar::{Grammar, GrammarState};
+
+mod grammar;
+mod json_schema;
+
+/// Maximum size in bytes of a compiled regex DFA, and of the memory used while compiling it, so
+/// that a pathological regex or JSON schema fails to compile instead of exhausting memory.
+const DFA_SIZE_LIMIT: usize = 64 << 20;
+/// The allowed tokens are cached for this many automaton states at most. The cache is cleared
+/// when it is full, which bounds its memory for constraints which go through many states.
+const MAX_CACHED_MASKS: usize = 256;
+
+/// A constraint on the generated text.
+#[derive(Clone, Debug)]
+pub enum Constraint {
+    /// The whole completion must match this regex.
+    Regex(String),
+    /// The completion must be a JSON value valid against this schema. Supports the `type`,
+    /// `properties`, `required`, `items`, `minItems`, `maxItems`, `enum`, `const`, `anyOf`,
+    /// `oneOf`, `minLength`, `maxLength`, `pattern` and local `$ref` keywords. A `pattern` is
+    /// matched against the string as written in JSON, escapes included, and must not match an
+    /// unescaped `"`.
+    JsonSchema(serde_json::Value),
+    /// The completion must be generated by the `root` rule of this GBNF grammar.
+    Grammar(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ConstraintError {
+    #[error("Invalid regex: {0}")]
+    Regex(String),
+    #[error("Unsupported JSON schema: {0}")]
+    JsonSchema(String),
+    #[error("Invalid grammar: {0}")]
+    Grammar(String),
+    #[error("Token {0} is not allowed by the constraint.")]
+    DisallowedToken(u32),
+}
+
+#[derive(Debug)]
+enum Automaton {
+    Regex(dense::DFA<Vec<u32>>),
+    Grammar(Grammar),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum State {
+    Regex(StateID),
+    Grammar(GrammarState),
+}
+
+impl Automaton {
+    fn regex(pattern: &str) -> Result<Self> {
+        let dfa = dense::Builder::new()
+            // Every match must be kept, not only the leftmost-first one, as the text may continue
+            // past a shorter match into a longer one.
+            .configure(
+                dense::Config::new()
+                    .start_kind(StartKind::Anchored)
+                    .match_kind(MatchKind::All)
+                    .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+                    .determinize_size_limit(Some(DFA_SIZE_LIMIT)),
+            )
+            .build(pattern)
+            .map_err(|e| ConstraintError::Regex(e.to_string()))?;
+        Ok(Self::Regex(dfa))
+    }
+
+    fn start(&self) -> Result<State> {
+        Ok(match self {
+            Self::Regex(dfa) => State::Regex(
+                dfa.start_state(&start::Config::new().anchored(Anchored::Yes))
+                    .map_err(|e| ConstraintError::Regex(e.to_string()))?,
+            ),
+            Self::Grammar(grammar) => State::Grammar(grammar.start()),
+        })
+    }
+
+    /// The state after `bytes`, or `None` if no match can start with them.
+    fn step(&self, state: &State, bytes: &[u8]) -> Option<State> {
+        match (self, state) {
+            (Self::Regex(dfa), State::Regex(state)) => {
+                let mut state = *state;
+                for byte in bytes {
+                    state = dfa.next_state(state, *byte);
+                    if dfa.is_dead_state(state) {
+                        return None;
+                    }
+                }
+                Some(State::Regex(state))
+            }
+            (Self::Grammar(grammar), State::Grammar(state)) => {
+                grammar.step(state, bytes).map(State::Grammar)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the input so far is a complete match.
+    fn is_accepting(&self, state: &State) -> bool {
+        match (self, state) {
+            (Self::Regex(dfa), State::Regex(state)) => {
+                dfa.is_match_state(dfa.next_eoi_state(*state))
+            }
+            (Self::Grammar(grammar), State::Grammar(state)) => grammar.is_accepting(state),
+            _ => false,
+        }
+    }
+}
+
+/// The bytes of every vocab token, or `None` for special tokens, which never match.
+#[derive(Debug)]
+struct TokenBytes(Vec<Option<Vec<u8>>>);
+
+impl TokenBytes {
+    fn new(tokenizer: &Tokenizer) -> Self {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let byte_decoder = byte_level.then(|| {
+            bytes_to_unicode()
+                .into_iter()
+                .map(|(byte, c)| (c, byte))
+                .collect::<HashMap<_, _>>()
+        });
+        let special = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect::<HashSet<_>>();
+
+        let tokens = (0..tokenizer.get_vocab_size(true) as u32)
+            .map(|id| {
+                if special.contains(&id) {
+                    return None;
+                }
+                let piece = tokenizer.id_to_token(id)?;
+                // Byte fallback tokens such as `<0x0A>` stand for one raw byte.
+                if let Some(byte) = piece
+                    .strip_prefix("<0x")
+                    .and_then(|hex| hex.strip_suffix('>'))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    return Some(vec![byte]);
+                }
+                match &byte_decoder {
+                    Some(byte_decoder) => piece
+                        .chars()
+                        .map(|c| byte_decoder.get(&c).copied())
+                        .collect(),
+                    None => Some(piece.replace('\u{2581}', " ").into_bytes()),
+                }
+            })
+            .collect();
+        Self(tokens)
+    }
+}
+
+/// The printable characters GPT-2 style byte-level tokenizers use for each byte.
+fn bytes_to_unicode() -> Vec<(u8, char)> {
+    let mut n = 0;
+    (0..=255u8)
+        .map(|byte| {
+            let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+            let c = if printable {
+                byte as u32
+            } else {
+                n += 1;
+                255 + n
+            };
+            // NOTE Unwrap reasoning: All of these are valid code points.
+            (byte, char::from_u32(c).unwrap())
+        })
+        .collect()
+}
+
+/// The state of a [`Constraint`] while generating one sequence. Cloning is cheap: the compiled
+/// automaton and the cache of allowed tokens per state are shared.
+#[derive(Clone)]
+pub struct TokenConstraint {
+    automaton: Arc<Automaton>,
+    state: State,
+    vocab: Arc<TokenBytes>,
+    eos_toks: Vec<u32>,
+    masks: Arc<Mutex<HashMap<State, Arc<Vec<bool>>>>>,
+}
+
+impl TokenConstraint {
+    pub fn new(constraint: &Constraint, tokenizer: &Tokenizer, eos_toks: &[u32]) -> Result<Self> {
+        let automaton = match constraint {
+            Constraint::Regex(pattern) => Automaton::regex(pattern)?,
+            Constraint::JsonSchema(schema) => Automaton::regex(&json_schema::to_regex(schema)?)?,
+            Constraint::Grammar(grammar) => Automaton::Grammar(Grammar::parse(grammar)?),
+        };
+        let state = automaton.start()?;
+        Ok(Self {
+            automaton: Arc::new(automaton),
+            state,
+            vocab: Arc::new(TokenBytes::new(tokenizer)),
+            eos_toks: eos_toks.to_vec(),
+            masks: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Which tokens may be generated next, indexed by token id. EOS is allowed once the text is
+    /// a complete match, or if no other token is allowed.
+    pub fn allowed_tokens(&self) -> Arc<Vec<bool>> {
+        if let Some(mask) = get_mut_arcmutex!(self.masks).get(&self.state) {
+            return mask.clone();
+        }
+        let mut mask = self
+            .vocab
+            .0
+            .iter()
+            .map(|bytes| {
+                bytes.as_ref().is_some_and(|bytes| {
+                    !bytes.is_empty() && self.automaton.step(&self.state, bytes).is_some()
+                })
+            })
+            .collect::<Vec<_>>();
+        let dead_end = !mask.iter().any(|allowed| *allowed);
+        let eos_allowed = dead_end || self.automaton.is_accepting(&self.state);
+        for eos_tok in &self.eos_toks {
+            if let Some(eos) = mask.get_mut(*eos_tok as usize) {
+                *eos = eos_allowed;
+            }
+        }
+        let mask = Arc::new(mask);
+        let mut masks = get_mut_arcmutex!(self.masks);
+        if masks.len() == MAX_CACHED_MASKS {
+            masks.clear();
+        }
+        masks.insert(self.state.clone(), mask.clone());
+        mask
+    }
+
+    /// Advance the automaton over the generated token.
+    pub fn advance(&mut self, tok: u32) -> Result<()> {
+        if self.eos_toks.contains(&tok) {
+            return Ok(());
+        }
+        let state = self
+            .vocab
+            .0
+            .get(tok as usize)
+            .and_then(|bytes| bytes.as_ref())
+            .and_then(|bytes| self.automaton.step(&self.state, bytes))
+            .ok_or(ConstraintError::DisallowedToken(tok))?;
+        self.state = state;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{models::wordlevel::WordLevel, AddedToken, Tokenizer};
+
+    use super::{Constraint, TokenConstraint};
+
+    const EOS: u32 = 0;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            ("</s>", EOS),
+            ("a", 1),
+            ("b", 2),
+            ("ab", 3),
+            ("\u{2581}a", 4),
+        ]
+        .into_iter()
+        .map(|(token, id)| (token.to_string(), id))
+        .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).build().unwrap());
+        tokenizer.add_special_tokens(&[AddedToken::from("</s>", true)]);
+        tokenizer
+    }
+
+    fn allowed(constraint: &TokenConstraint) -> Vec<u32> {
+        let mask = constraint.allowed_tokens();
+        (0..mask.len() as u32)
+            .filter(|tok| mask[*tok as usize])
+            .collect()
+    }
+
+    #[test]
+    fn regex_allows_tokens_continuing_a_match() {
+        let constraint = Constraint::Regex("a+b".to_string());
+        let mut constraint = TokenConstraint::new(&constraint, &tokenizer(), &[EOS]).unwrap();
+        assert_eq!(allowed(&constraint), vec![1, 3]);
+        constraint.advance(1).unwrap();
+        assert_eq!(allowed(&constraint), vec![1, 2, 3]);
+        constraint.advance(2).unwrap();
+        assert_eq!(allowed(&constraint), vec![EOS]);
+        assert!(constraint.advance(1).is_err());
+    }
+
+    #[test]
+    fn regex_continues_past_a_shorter_match() {
+        let constraint = Constraint::Regex("a|ab".to_string());
+        let mut constraint = TokenConstraint::new(&constraint, &tokenizer(), &[EOS]).unwrap();
+        assert_eq!(allowed(&constraint), vec![1, 3]);
+        constraint.advance(1).unwrap();
+        assert_eq!(allowed(&constraint), vec![EOS, 2]);
+    }
+
+    #[test]
+    fn regex_dfa_size_is_limited() {
+        // Determinizing this takes a state for each combination of the last 24 bytes.
+        let constraint = Constraint::Regex("[ab]*a[ab]{24}".to_string());
+        assert!(TokenConstraint::new(&constraint, &tokenizer(), &[EOS]).is_err());
+    }
+
+    #[test]
+    fn sentencepiece_word_boundaries_are_spaces() {
+        let constraint = Constraint::Regex(" a".to_string());
+        let constraint = TokenConstraint::new(&constraint, &tokenizer(), &[EOS]).unwrap();
+        assert_eq!(allowed(&constraint), vec![4]);
+    }
+
+    #[test]
+    fn grammar_allows_tokens_continuing_a_match() {
+        let constraint = Constraint::Grammar(r#"root ::= "a" "b"?"#.to_string());
+        let mut constraint = TokenConstraint::new(&constraint, &tokenizer(), &[EOS]).unwrap();
+        assert_eq!(allowed(&constraint), vec![1, 3]);
+        constraint.advance(1).unwrap();
+        assert_eq!(allowed(&constraint), vec![EOS, 2]);
+    }
+}
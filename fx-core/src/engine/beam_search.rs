@@ -0,0 +1,205 @@
+use std::{cell::RefCell, error::Error, rc::Rc, sync::mpsc::Sender};
+
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use tokenizers::Tokenizer;
+
+use crate::{
+    deref_refcell,
+    request::{BeamSearchParams, Sequence, SequenceState},
+    response::{Beam, FinishReason, Response, Usage},
+    sampler::{log_softmax, sorted_desc, Logprobs},
+};
+
+/// The running and finished beams of one beam search request. All running beams are stepped
+/// together: each step, the best `beam_width` continuations over all beams are kept, forking a
+/// beam (and sharing its KV cache) when it has more than one surviving continuation.
+pub struct BeamSearch {
+    params: BeamSearchParams,
+    beams: Vec<Rc<RefCell<Sequence>>>,
+    finished: Vec<Beam>,
+    responder: Sender<Response>,
+    /// Usage of the latest stepped beam, for the prompt and the timings of the search.
+    latest_usage: Usage,
+    /// Number of tokens generated for all beams, counting those which forked beams share once.
+    generated: usize,
+}
+
+impl BeamSearch {
+    pub fn new(seq: Rc<RefCell<Sequence>>, params: BeamSearchParams) -> Self {
+        let (responder, latest_usage) = {
+            let seq = deref_refcell!(seq);
+            (seq.responder(), seq.usage())
+        };
+        Self {
+            params,
+            beams: vec![seq],
+            finished: Vec::new(),
+            responder,
+            latest_usage,
+            generated: 0,
+        }
+    }
+
+    /// Usage of the whole search so far.
+    fn usage(&self) -> Usage {
+        Usage {
+            completion_tokens: self.generated,
+            ..self.latest_usage
+        }
+    }
+
+    pub fn contains(&self, seq: &Rc<RefCell<Sequence>>) -> bool {
+        self.beams.iter().any(|beam| Rc::ptr_eq(beam, seq))
+    }
+
+    fn score(&self, seq: &Sequence) -> f32 {
+        let len = seq.completion_tokens().len().max(1) as f32;
+        seq.cumulative_logprob() / len.powf(self.params.length_penalty)
+    }
+
+    /// Advance every running beam given its logits. `next_id` is used for the ids of forked beams.
+    /// Returns the beams which were forked, which must be scheduled alongside the others.
+    pub fn step(
+        &mut self,
+        logits: Vec<(Rc<RefCell<Sequence>>, Tensor)>,
+        eos_toks: &[u32],
+        tokenizer: &Tokenizer,
+        next_id: &mut usize,
+    ) -> Result<Vec<Rc<RefCell<Sequence>>>> {
+        let width = self.params.beam_width;
+
+        // The best `2 * width` continuations over all beams, so that at least `width` of them
+        // remain if up to `width` finish.
+        let mut candidates = Vec::new();
+        for (parent, logits) in logits.iter() {
+            let mut logits = logits
+                .flatten_all()?
+                .to_dtype(DType::F32)?
+                .to_vec1::<f32>()?;
+            let cumulative = {
+                let parent = deref_refcell!(parent);
+                parent.process_logits(&mut logits, parent.len())?;
+                parent.cumulative_logprob()
+            };
+            let mut top = sorted_desc(&log_softmax(&logits));
+            // Tokens masked by the logits processors can never be chosen.
+            top.retain(|(_, logprob)| logprob.is_finite());
+            top.truncate(2 * width);
+            candidates.extend(
+                top.into_iter()
+                    .map(|(tok, logprob)| (cumulative + logprob, parent, tok, logprob)),
+            );
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        candidates.truncate(2 * width);
+        // Each running beam generated one token, which its forks share.
+        self.generated += logits.len();
+
+        let mut beams = Vec::new();
+        let mut forked = Vec::new();
+        for (rank, (_, parent, tok, logprob)) in candidates.into_iter().enumerate() {
+            let mut child = deref_refcell!(parent).fork(*next_id)?;
+            *next_id += 1;
+            child.add_token(
+                Logprobs {
+                    token: tok,
+                    logprob,
+                    top_logprobs: Vec::new(),
+                },
+                tokenizer,
+            )?;
+            if let Some(stop_reason) = child.is_done(eos_toks) {
+                // A finished beam only counts if it would have been kept as a running beam.
+                if rank < width {
+                    self.finished.push(Beam {
+                        text: child.completion().to_string(),
+                        score: self.score(&child),
+                        token_ids: child
+                            .returns_token_ids()
+                            .then(|| child.completion_tokens().to_vec()),
+                        stop_reason,
+                        finish_reason: stop_reason.into(),
+                        usage: child.usage(),
+                    });
+                }
+                continue;
+            }
+            let child = Rc::new(RefCell::new(child));
+            forked.push(child.clone());
+            beams.push(child);
+            if beams.len() == width {
+                break;
+            }
+        }
+
+        for (parent, _) in logits {
+            let parent = deref_refcell!(parent);
+            parent.set_state(SequenceState::Pruned);
+            self.latest_usage = parent.usage();
+        }
+        self.beams = beams;
+        if self.is_done() {
+            self.prune();
+            return Ok(Vec::new());
+        }
+        Ok(forked)
+    }
+
+    /// Whether the finished beams cannot be improved upon anymore.
+    pub fn is_done(&self) -> bool {
+        if self.beams.is_empty() {
+            return true;
+        }
+        if self.finished.len() < self.params.beam_width {
+            return false;
+        }
+        if self.params.early_stopping {
+            return true;
+        }
+        // As in `transformers`, the best running beam is assumed not to improve by growing.
+        let best_running = self
+            .beams
+            .iter()
+            .map(|beam| self.score(&deref_refcell!(beam)))
+            .fold(f32::NEG_INFINITY, f32::max);
+        let worst_finished = self
+            .finished
+            .iter()
+            .map(|beam| beam.score)
+            .fold(f32::INFINITY, f32::min);
+        best_running <= worst_finished
+    }
+
+    /// Stop all running beams.
+    pub fn prune(&mut self) {
+        for beam in self.beams.drain(..) {
+            deref_refcell!(beam).set_state(SequenceState::Pruned);
+        }
+    }
+
+    /// Send the best `num_returned` finished beams, best first.
+    pub fn finish(mut self) {
+        self.finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.finished.truncate(self.params.num_returned);
+        let usage = self.usage();
+        // The search is over, so there is nothing to cancel if the receiver went away.
+        let _ = self.responder.send(Response::Beams {
+            beams: self.finished,
+            usage,
+        });
+    }
+
+    /// Stop all running beams and send `e`.
+    pub fn fail(self, e: Box<dyn Error + Send + Sync>) {
+        for beam in &self.beams {
+            deref_refcell!(beam).set_state(SequenceState::Error);
+        }
+        // The search is over, so there is nothing to cancel if the receiver went away.
+        let _ = self.responder.send(Response::Failed {
+            error: e,
+            finish_reason: FinishReason::Error,
+            usage: self.usage(),
+        });
+    }
+}
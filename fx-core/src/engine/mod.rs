@@ -1,162 +1,450 @@
 use std::{
     cell::RefCell,
     collections::VecDeque,
+    error::Error,
     iter::zip,
     rc::Rc,
     sync::{mpsc::Receiver, Mutex},
 };
 
+use anyhow::Result;
 use candle_core::Tensor;
-use candle_sampling::logits_processor::{LogitsProcessor, Logprobs, SamplingMethod};
 
 use crate::{
-    deref_mut_refcell, deref_refcell, get_mut_arcmutex, handle_seq_error,
+    constraints::TokenConstraint,
+    deref_mut_refcell, deref_refcell, get_mut_arcmutex, handle_seq_error, logits_processors,
     pipeline::Pipeline,
-    request::{Request, Sequence, SequenceState},
-    response::Response,
-    scheduler::{Scheduler, SchedulerOutput},
+    request::{check_prompt_tokens, Request, Sequence, SequenceGroup, SequenceState},
+    response::{Choice, CompletionChunk, FinishReason, Response},
+    sampler::{Logprobs, Sampler},
+    scheduler::Scheduler,
+    stopping_criteria,
 };
 
-const SEED: u64 = 0;
+use self::beam_search::BeamSearch;
+pub use self::speculative::{Drafter, Speculative};
+
+mod beam_search;
+mod speculative;
+
+/// Maximum number of sequences run in one forward pass.
+const MAX_RUNNING_SEQS: usize = 16;
 
 pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Box<Mutex<dyn Pipeline>>,
     requests: VecDeque<Request>,
     scheduler: Scheduler<VecDeque<Rc<RefCell<Sequence>>>>,
+    beam_searches: Vec<BeamSearch>,
+    /// Sequences which are forked into the other choices of their request after prefill, with the
+    /// samplers of those choices.
+    pending_choices: Vec<(Rc<RefCell<Sequence>>, Vec<Sampler>)>,
+    speculative: Option<Speculative>,
     id: usize,
 }
 
 impl Engine {
-    pub fn new(rx: Receiver<Request>, pipeline: Box<Mutex<dyn Pipeline>>) -> Self {
+    pub fn new(
+        rx: Receiver<Request>,
+        pipeline: Box<Mutex<dyn Pipeline>>,
+        speculative: Option<Speculative>,
+    ) -> Self {
         Self {
             rx,
             pipeline,
             requests: VecDeque::new(),
-            scheduler: Scheduler::new(),
+            scheduler: Scheduler::new(MAX_RUNNING_SEQS),
+            beam_searches: Vec::new(),
+            pending_choices: Vec::new(),
+            speculative,
             id: 0,
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            if let Ok(request) = self.rx.recv() {
+            // Only block waiting for requests when there is nothing else to do.
+            if self.scheduler.is_idle() {
+                match self.rx.recv() {
+                    Ok(request) => self.add_request(request),
+                    // All senders are gone, so no more requests can arrive.
+                    Err(_) => return,
+                }
+            }
+            while let Ok(request) = self.rx.try_recv() {
                 self.add_request(request);
             }
             let scheduled = self.scheduler.schedule();
-            self.clone_in_cache(&scheduled);
-            let logits = get_mut_arcmutex!(self.pipeline).forward(scheduled.seqs.clone());
-            self.clone_out_cache(&scheduled);
+            let (speculative, seqs): (Vec<_>, Vec<_>) = scheduled
+                .seqs
+                .iter()
+                .cloned()
+                .partition(|seq| self.can_speculate(seq));
+            if !speculative.is_empty() {
+                self.step_speculative(&speculative);
+            }
+            if seqs.is_empty() {
+                continue;
+            }
+            let seqs: Box<[_]> = seqs.into();
+            for seq in seqs.iter() {
+                deref_mut_refcell!(seq).start_prefill();
+            }
+            let logits = match get_mut_arcmutex!(self.pipeline).forward(seqs.clone()) {
+                Ok(logits) => logits,
+                Err(e) => {
+                    for seq in seqs.iter() {
+                        // Skip the beams of a search which already failed with another beam.
+                        if !deref_refcell!(seq).is_running() {
+                            continue;
+                        }
+                        match self.beam_searches.iter().position(|s| s.contains(seq)) {
+                            Some(i) => self.beam_searches.remove(i).fail(e.to_string().into()),
+                            None => {
+                                // The choices which were to be forked off it after prefill fail too.
+                                self.pending_choices
+                                    .retain(|(pending, _)| !Rc::ptr_eq(pending, seq));
+                                Self::fail_seq(seq, e.to_string().into());
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
 
-            // TODO: Unwrapping is certainly incorrect. Must handle error
-            let logits = logits.unwrap();
-            let seqs_len = scheduled.seqs.len();
+            let seqs_len = seqs.len();
+            // NOTE Unwrap reasoning: The logits have one row per sequence.
             let logits_seq = logits.chunk(seqs_len, 0).unwrap();
             debug_assert_eq!(logits_seq.len(), seqs_len);
-            for (logits_per_seq, seq) in zip(logits_seq, scheduled.seqs.iter()) {
-                let next_token: Logprobs = handle_seq_error!(
-                    get_mut_arcmutex!(self.pipeline).sample(logits_per_seq, seq.clone()),
-                    deref_refcell!(seq).responder()
-                );
-                let next_token = next_token.token as u32;
-                deref_mut_refcell!(seq).add_token(next_token);
-                if let Some(state) = deref_refcell!(seq)
-                    .is_done(next_token, get_mut_arcmutex!(self.pipeline).eos_tok())
-                {
-                    deref_mut_refcell!(seq).set_state(SequenceState::Done(state));
+            let mut beam_logits = vec![Vec::new(); self.beam_searches.len()];
+            for (logits_per_seq, seq) in zip(logits_seq, seqs.iter()) {
+                // Beams are advanced together once all their logits are known.
+                if let Some(i) = self.beam_searches.iter().position(|s| s.contains(seq)) {
+                    beam_logits[i].push((seq.clone(), logits_per_seq));
+                    continue;
+                }
+                let choices = match self.fork_choices(seq) {
+                    Ok(choices) => choices,
+                    Err(e) => {
+                        Self::fail_seq(seq, e.into());
+                        continue;
+                    }
+                };
+                for choice in choices {
+                    self.sample(&choice, logits_per_seq.clone());
                 }
+                self.sample(seq, logits_per_seq);
             }
+            self.step_beam_searches(beam_logits);
         }
     }
 
-    fn clone_in_cache(&self, scheduled: &SchedulerOutput) {
-        let mut new_cache = Vec::new();
-        for layer in 0..get_mut_arcmutex!(self.pipeline).num_hidden_layers() {
-            let mut k_vec = Vec::new();
-            let mut v_vec = Vec::new();
-            for seq in scheduled.seqs.iter() {
-                let seq = deref_refcell!(seq);
-                let cache = seq.cache().lock();
-                let cache = cache.get(layer).unwrap();
-                // TODO: Unwrapping is certainly incorrect. Need to find a way to handle some caches with values, some without.
-                let cache = cache.as_ref().unwrap();
-                k_vec.push(cache.0.clone().unsqueeze(0).unwrap());
-                v_vec.push(cache.1.clone().unsqueeze(0).unwrap());
+    /// Whether `seq` is decoded speculatively. Only sampled, unconstrained sequences are, once
+    /// their prompt has been prefilled and their other choices forked.
+    fn can_speculate(&self, seq: &Rc<RefCell<Sequence>>) -> bool {
+        if self.speculative.is_none()
+            || self.beam_searches.iter().any(|search| search.contains(seq))
+        {
+            return false;
+        }
+        let seq = deref_refcell!(seq);
+        seq.is_prefilled() && !seq.is_constrained()
+    }
+
+    /// Propose and verify several tokens for each of `seqs` and add the accepted ones.
+    fn step_speculative(&mut self, seqs: &[Rc<RefCell<Sequence>>]) {
+        // NOTE Unwrap reasoning: Sequences are only decoded speculatively if it is enabled.
+        let speculative = self.speculative.as_mut().unwrap();
+        let tokens = match speculative.step(&*self.pipeline, seqs) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                for seq in seqs {
+                    speculative.remove(seq);
+                    Self::fail_seq(seq, e.to_string().into());
+                }
+                return;
+            }
+        };
+        for (seq, tokens) in zip(seqs, tokens) {
+            for next_token in tokens {
+                if let Err(e) = self.add_token(seq, next_token) {
+                    Self::fail_seq(seq, e.into());
+                }
+                if !deref_refcell!(seq).is_running() {
+                    break;
+                }
             }
-            // NOTE Unwrap reasoning: We have the correct dims
-            new_cache.push(Some((
-                Tensor::cat(&k_vec, 0).unwrap(),
-                Tensor::cat(&v_vec, 0).unwrap(),
-            )));
+            if !deref_refcell!(seq).is_running() {
+                // NOTE Unwrap reasoning: Sequences are only decoded speculatively if it is enabled.
+                self.speculative.as_mut().unwrap().remove(seq);
+            }
+        }
+    }
+
+    /// If `seq` was just prefilled and its request has more choices, fork them off it and schedule
+    /// them. They share the prompt KV cache with `seq`.
+    fn fork_choices(&mut self, seq: &Rc<RefCell<Sequence>>) -> Result<Vec<Rc<RefCell<Sequence>>>> {
+        let Some(i) = self
+            .pending_choices
+            .iter()
+            .position(|(pending, _)| Rc::ptr_eq(pending, seq))
+        else {
+            return Ok(Vec::new());
+        };
+        let (_, samplers) = self.pending_choices.swap_remove(i);
+        let mut choices = Vec::with_capacity(samplers.len());
+        for (i, sampler) in samplers.into_iter().enumerate() {
+            let choice = deref_refcell!(seq).fork_choice(self.id, i + 1, sampler)?;
+            self.id += 1;
+            choices.push(Rc::new(RefCell::new(choice)));
+        }
+        // Only schedule the choices once all of them were forked, as the request fails otherwise.
+        for choice in &choices {
+            self.scheduler.add_running(choice.clone());
+        }
+        Ok(choices)
+    }
+
+    fn sample(&self, seq: &Rc<RefCell<Sequence>>, logits: Tensor) {
+        // A sibling earlier in the batch may have failed or cancelled the request.
+        deref_refcell!(seq).stop_with_group();
+        if !deref_refcell!(seq).is_running() {
+            return;
+        }
+        let sampled = get_mut_arcmutex!(self.pipeline).sample(logits, seq.clone());
+        let next_token: Logprobs = match sampled {
+            Ok(next_token) => next_token,
+            Err(e) => {
+                Self::fail_seq(seq, e.into());
+                return;
+            }
+        };
+        if let Err(e) = self.add_token(seq, next_token) {
+            Self::fail_seq(seq, e.into());
         }
-        *get_mut_arcmutex!(self.pipeline).cache().lock() = new_cache;
     }
 
-    fn clone_out_cache(&self, scheduled: &SchedulerOutput) {
-        for layer in 0..get_mut_arcmutex!(self.pipeline).num_hidden_layers() {
+    /// Advance each beam search given the logits of its beams, and send the results of those which
+    /// are done.
+    fn step_beam_searches(&mut self, beam_logits: Vec<Vec<(Rc<RefCell<Sequence>>, Tensor)>>) {
+        let (eos_toks, tokenizer) = {
             let pipeline = get_mut_arcmutex!(self.pipeline);
-            let cache = pipeline.cache().lock();
-            let cache = cache.get(layer).unwrap();
-            let k_cache = cache.as_ref().unwrap().0.clone();
-            let v_cache = cache.as_ref().unwrap().1.clone();
-
-            let k_caches = k_cache.chunk(scheduled.seqs.len(), 0).unwrap();
-            debug_assert_eq!(k_caches.len(), scheduled.seqs.len());
-            let v_caches = v_cache.chunk(scheduled.seqs.len(), 0).unwrap();
-            debug_assert_eq!(v_caches.len(), scheduled.seqs.len());
-
-            for (seq_i, seq) in scheduled.seqs.iter().enumerate() {
-                let seq = deref_refcell!(seq);
-                let mut seq_cache = seq.cache().lock();
-                let seq_cache = seq_cache.get_mut(layer).unwrap();
-                *seq_cache = Some((
-                    k_caches.get(seq_i).unwrap().clone(),
-                    v_caches.get(seq_i).unwrap().clone(),
-                ));
+            (pipeline.eos_toks().to_vec(), pipeline.tokenizer())
+        };
+        let mut searches = Vec::with_capacity(self.beam_searches.len());
+        for (mut search, logits) in zip(self.beam_searches.drain(..), beam_logits) {
+            if logits.is_empty() {
+                searches.push(search);
+                continue;
+            }
+            match search.step(logits, &eos_toks, &tokenizer, &mut self.id) {
+                Ok(forked) => {
+                    for seq in forked {
+                        self.scheduler.add_running(seq);
+                    }
+                }
+                Err(e) => {
+                    search.fail(e.into());
+                    continue;
+                }
+            }
+            if search.is_done() {
+                search.finish();
+            } else {
+                searches.push(search);
             }
         }
+        self.beam_searches = searches;
     }
 
-    fn add_request(&mut self, request: Request) {
-        let prompt = handle_seq_error!(
-            get_mut_arcmutex!(self.pipeline).tokenize_prompt(&request.prompt),
-            request.response
-        );
-        let sampling_method = match (request.sampling_params.top_k, request.sampling_params.top_p) {
-            (Some(topk), None) => SamplingMethod::TopK(topk),
-            (None, Some(topp)) => SamplingMethod::TopP(topp),
-            (None, None) | (Some(_), Some(_)) => {
-                // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
-                request
-                    .response
-                    .send(Response::Error(
-                        "Please specify either topk or topp.".into(),
-                    ))
-                    .unwrap();
-                return;
+    /// Add the sampled token to the sequence, then send the new text (if streaming) or add the
+    /// finished choice to its group (if done).
+    fn add_token(&self, seq: &Rc<RefCell<Sequence>>, next_token: Logprobs) -> Result<()> {
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let mut seq = deref_mut_refcell!(seq);
+        let tok = next_token.token;
+        let logprobs = seq.add_token(next_token, &pipeline.tokenizer())?;
+        let stop_reason = seq.is_done(pipeline.eos_toks());
+        let usage = stop_reason.map(|_| seq.usage());
+        if let (Some(_), Some(path)) = (stop_reason, seq.snapshot_path()) {
+            seq.save_snapshot(path)?;
+        }
+
+        if seq.is_streaming() {
+            let text = seq.take_stream_text(stop_reason.is_some());
+            deref_mut_refcell!(seq.group()).send_chunk(CompletionChunk {
+                index: seq.index(),
+                text,
+                logprobs,
+                token_ids: seq.returns_token_ids().then(|| vec![tok]),
+                stop_reason,
+                finish_reason: stop_reason.map(FinishReason::from),
+                usage,
+            });
+        }
+        if let (Some(stop_reason), Some(usage)) = (stop_reason, usage) {
+            seq.set_state(SequenceState::Done(stop_reason));
+            if !seq.is_streaming() {
+                deref_mut_refcell!(seq.group()).add_choice(
+                    seq.cumulative_logprob(),
+                    Choice {
+                        index: seq.index(),
+                        text: seq.completion().to_string(),
+                        logprobs: seq.logprobs().map(<[_]>::to_vec),
+                        token_ids: seq
+                            .returns_token_ids()
+                            .then(|| seq.completion_tokens().to_vec()),
+                        stop_reason,
+                        finish_reason: stop_reason.into(),
+                        usage,
+                    },
+                );
             }
+        }
+        seq.stop_with_group();
+        Ok(())
+    }
+
+    fn fail_seq(seq: &Rc<RefCell<Sequence>>, e: Box<dyn Error + Send + Sync>) {
+        let mut seq = deref_mut_refcell!(seq);
+        seq.set_state(SequenceState::Error);
+        let usage = seq.usage();
+        if seq.is_streaming() {
+            let text = seq.take_stream_text(true);
+            deref_mut_refcell!(seq.group()).send_chunk(CompletionChunk {
+                index: seq.index(),
+                text,
+                logprobs: None,
+                token_ids: seq.returns_token_ids().then(Vec::new),
+                stop_reason: None,
+                finish_reason: Some(FinishReason::Error),
+                usage: Some(usage),
+            });
+        }
+        deref_mut_refcell!(seq.group()).fail(e, usage);
+    }
+
+    fn add_request(&mut self, mut request: Request) {
+        let vocab_size = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            pipeline
+                .generation_config()
+                .apply(&mut request.sampling_params);
+            pipeline.vocab_size()
+        };
+        if let Err(e) = request.sampling_params.validate(vocab_size) {
+            // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
+            request.response.send(Response::Error(e.into())).unwrap();
+            return;
+        }
+        if request.sampling_params.beam_search.is_some() && request.is_streaming {
+            // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
+            request
+                .response
+                .send(Response::Error(
+                    "Beam search does not support streaming.".into(),
+                ))
+                .unwrap();
+            return;
+        }
+        let (n, best_of) = (
+            request.sampling_params.n(),
+            request.sampling_params.best_of(),
+        );
+        if request.save_snapshot.is_some()
+            && (request.sampling_params.beam_search.is_some() || best_of > 1)
+        {
+            // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
+            request
+                .response
+                .send(Response::Error(
+                    "Snapshots cannot be saved with beam search or `best_of` above 1.".into(),
+                ))
+                .unwrap();
+            return;
+        }
+        if best_of > n && request.is_streaming {
+            // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
+            request
+                .response
+                .send(Response::Error(
+                    "`best_of` greater than `n` does not support streaming.".into(),
+                ))
+                .unwrap();
+            return;
+        }
+        let prompt = match &request.prompt_token_ids {
+            // The prompt is replaced by the tokens of the snapshot below.
+            _ if request.restore_snapshot.is_some() => Vec::new(),
+            Some(prompt) => prompt.clone(),
+            None => handle_seq_error!(
+                get_mut_arcmutex!(self.pipeline).tokenize_prompt(&request.prompt),
+                request.response
+            ),
+        };
+        let (num_hidden_layers, tokenizer, eos_toks) = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            (
+                pipeline.num_hidden_layers(),
+                pipeline.tokenizer(),
+                pipeline.eos_toks().to_vec(),
+            )
+        };
+        let constraint = match &request.sampling_params.constraint {
+            Some(constraint) => Some(handle_seq_error!(
+                TokenConstraint::new(constraint, &tokenizer, &eos_toks),
+                request.response
+            )),
+            None => None,
         };
-        let seq = Sequence::new_waiting(
+        let logits_processors = logits_processors::chain(&request.sampling_params, &eos_toks);
+        let stopping_criteria = handle_seq_error!(
+            stopping_criteria::chain(&request.sampling_params),
+            request.response
+        );
+        // Each choice gets its own seed, derived from the request's one to stay reproducible.
+        let seed = request.sampling_params.seed.unwrap_or_else(rand::random);
+        let mut samplers = (0..best_of as u64).map(|i| {
+            Sampler::new(
+                seed.wrapping_add(i),
+                &request.sampling_params,
+                tokenizer.clone(),
+            )
+        });
+        let group = SequenceGroup::new(n, best_of, request.response.clone());
+        let mut seq = Sequence::new_waiting(
             prompt,
             self.id,
-            get_mut_arcmutex!(self.pipeline).num_hidden_layers(),
-            request.response.clone(),
-            LogitsProcessor::new(
-                SEED,
-                request.sampling_params.temperature,
-                sampling_method,
-                request.sampling_params.top_n_logprobs,
-                get_mut_arcmutex!(self.pipeline).tokenizer(),
-                request.sampling_params.repeat_penalty,
-            ),
-            request
-                .sampling_params
-                .stop_toks
-                .clone()
-                .unwrap_or_default(),
+            num_hidden_layers,
+            Rc::new(RefCell::new(group)),
+            // NOTE Unwrap reasoning: `best_of` is at least 1.
+            samplers.next().unwrap(),
+            constraint,
+            logits_processors,
+            stopping_criteria,
+            &request.sampling_params,
+            request.is_streaming,
         );
+        if let Some(path) = &request.restore_snapshot {
+            let device = get_mut_arcmutex!(self.pipeline).device().clone();
+            handle_seq_error!(seq.restore_snapshot(path, &device), request.response);
+        }
+        // However the prompt was given, the model needs at least one token to run.
+        handle_seq_error!(
+            check_prompt_tokens(seq.get_tokens(), vocab_size),
+            request.response
+        );
+        seq.set_snapshot_path(request.save_snapshot.clone());
         self.id += 1;
 
+        let seq = Rc::new(RefCell::new(seq));
+        if let Some(beam_search) = request.sampling_params.beam_search.clone() {
+            self.beam_searches
+                .push(BeamSearch::new(seq.clone(), beam_search));
+        }
+        if best_of > 1 {
+            self.pending_choices.push((seq.clone(), samplers.collect()));
+        }
         self.requests.push_back(request);
         self.scheduler.add_seq(seq);
     }
@@ -0,0 +1,262 @@
+use std::{cell::RefCell, collections::HashMap, iter::zip, rc::Rc, sync::Mutex};
+
+use anyhow::Result;
+use candle_core::{DType, IndexOp, Tensor};
+use thiserror::Error;
+
+use crate::{
+    deref_mut_refcell, deref_refcell, get_mut_arcmutex, pipeline::Pipeline, request::Sequence,
+    sampler::Logprobs,
+};
+
+#[derive(Error, Debug)]
+enum SpeculativeError {
+    #[error("The draft model has {draft} tokens in its vocab, but the target model has {target}.")]
+    VocabMismatch { draft: usize, target: usize },
+    #[error("At least one draft token must be proposed.")]
+    NoDraftTokens,
+    #[error("`max_ngram` must be at least 1.")]
+    NoNgram,
+}
+
+/// How speculative tokens are proposed.
+pub enum Drafter {
+    /// Sample them from a smaller model with the same vocab.
+    Model(Box<Mutex<dyn Pipeline>>),
+    /// Copy the tokens which followed the last earlier occurrence of the sequence's last
+    /// `max_ngram` tokens (or fewer, down to 1) in its prompt and completion. This needs no draft
+    /// model and works well when the output copies from the prompt, such as for summarization or
+    /// code editing. Sequences without a match are decoded normally.
+    PromptLookup { max_ngram: usize },
+}
+
+/// Speculative decoding: a drafter proposes up to `num_draft_tokens` tokens for each sequence,
+/// which the target model verifies in a single forward pass. A proposal is accepted with
+/// probability `min(1, p / q)`, where `p` and `q` are the target and draft sampling distributions,
+/// and the first rejected one is replaced by a sample of the normalized `max(0, p - q)`. The
+/// output therefore follows the target distribution exactly.
+pub struct Speculative {
+    drafter: Drafter,
+    num_draft_tokens: usize,
+    /// The draft model's copy of each sequence by id, with its own KV cache.
+    draft_seqs: HashMap<usize, Rc<RefCell<Sequence>>>,
+}
+
+/// A proposed token and the distribution it was sampled from.
+type Proposal = (u32, Vec<(u32, f32)>);
+
+impl Speculative {
+    /// Check that `drafter` can propose `num_draft_tokens` tokens at a time for `target`.
+    pub fn validate(
+        target: &Mutex<dyn Pipeline>,
+        drafter: &Drafter,
+        num_draft_tokens: usize,
+    ) -> Result<()> {
+        if num_draft_tokens == 0 {
+            return Err(SpeculativeError::NoDraftTokens.into());
+        }
+        match drafter {
+            Drafter::Model(draft) => {
+                let target = get_mut_arcmutex!(target).vocab_size();
+                let draft = get_mut_arcmutex!(draft).vocab_size();
+                if draft != target {
+                    return Err(SpeculativeError::VocabMismatch { draft, target }.into());
+                }
+            }
+            Drafter::PromptLookup { max_ngram } => {
+                if *max_ngram == 0 {
+                    return Err(SpeculativeError::NoNgram.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings must have been checked with [`Speculative::validate`]. This is created on the
+    /// engine thread, as the draft copies of the sequences cannot be sent between threads.
+    pub fn new(drafter: Drafter, num_draft_tokens: usize) -> Self {
+        Self {
+            drafter,
+            num_draft_tokens,
+            draft_seqs: HashMap::new(),
+        }
+    }
+
+    /// Forget the draft copy of a sequence which is not running anymore.
+    pub fn remove(&mut self, seq: &Rc<RefCell<Sequence>>) {
+        self.draft_seqs.remove(deref_refcell!(seq).id());
+    }
+
+    /// Propose tokens for every (prefilled) sequence with the drafter and verify them with
+    /// `target`. Returns the tokens to add to each sequence, in order: the accepted proposals and
+    /// one token sampled from the target model. The KV caches of both models are rolled back to
+    /// the accepted tokens.
+    pub fn step(
+        &mut self,
+        target: &Mutex<dyn Pipeline>,
+        seqs: &[Rc<RefCell<Sequence>>],
+    ) -> Result<Vec<Vec<Logprobs>>> {
+        let proposals = if let Drafter::PromptLookup { max_ngram } = self.drafter {
+            seqs.iter()
+                .map(|seq| {
+                    lookup_ngram(
+                        deref_refcell!(seq).get_tokens(),
+                        max_ngram,
+                        self.num_draft_tokens,
+                    )
+                    .into_iter()
+                    // The proposals are certain, as far as the drafter is concerned.
+                    .map(|tok| (tok, vec![(tok, 1.)]))
+                    .collect()
+                })
+                .collect()
+        } else {
+            self.propose_with_model(seqs)?
+        };
+
+        for (seq, proposals) in zip(seqs, &proposals) {
+            let mut seq = deref_mut_refcell!(seq);
+            for (tok, _) in proposals {
+                seq.push_token(*tok);
+            }
+        }
+        let logits = get_mut_arcmutex!(target).forward_all(seqs.to_vec().into())?;
+
+        zip(seqs, zip(logits, proposals))
+            .map(|(seq, (logits, proposals))| {
+                verify(&mut deref_mut_refcell!(seq), &logits, &proposals)
+            })
+            .collect()
+    }
+
+    fn propose_with_model(&mut self, seqs: &[Rc<RefCell<Sequence>>]) -> Result<Vec<Vec<Proposal>>> {
+        let Drafter::Model(draft_model) = &self.drafter else {
+            return Ok(vec![Vec::new(); seqs.len()]);
+        };
+        let drafts = seqs
+            .iter()
+            .map(|seq| Self::sync_draft(&mut self.draft_seqs, draft_model, &deref_refcell!(seq)))
+            .collect::<Vec<_>>();
+
+        let mut proposals = vec![Vec::with_capacity(self.num_draft_tokens); seqs.len()];
+        for _ in 0..self.num_draft_tokens {
+            let logits = get_mut_arcmutex!(draft_model).forward(drafts.clone().into())?;
+            let logits = logits.chunk(drafts.len(), 0)?;
+            for ((draft, logits), proposals) in zip(zip(&drafts, logits), &mut proposals) {
+                let mut draft = deref_mut_refcell!(draft);
+                let len = draft.len();
+                let logprobs = sampling_logprobs(&mut draft, &logits, len)?;
+                let distribution = draft.sampler().distribution(&logprobs);
+                let tok = draft.sampler().pick(&distribution)?;
+                draft.push_token(tok);
+                proposals.push((tok, distribution));
+            }
+        }
+        Ok(proposals)
+    }
+
+    /// The draft copy of `seq` in `draft_seqs`, updated to its tokens. The draft KV cache is only
+    /// kept for the tokens both agree on.
+    fn sync_draft(
+        draft_seqs: &mut HashMap<usize, Rc<RefCell<Sequence>>>,
+        draft_model: &Mutex<dyn Pipeline>,
+        seq: &Sequence,
+    ) -> Rc<RefCell<Sequence>> {
+        let draft = draft_seqs
+            .entry(*seq.id())
+            .or_insert_with(|| {
+                let layers = get_mut_arcmutex!(draft_model).num_hidden_layers();
+                Rc::new(RefCell::new(seq.fork_draft(layers)))
+            })
+            .clone();
+        {
+            let mut draft = deref_mut_refcell!(draft);
+            let common = zip(draft.get_tokens(), seq.get_tokens())
+                .take_while(|(a, b)| a == b)
+                .count();
+            draft.truncate_tokens(common);
+            draft.cache().truncate(common);
+            for tok in &seq.get_tokens()[common..] {
+                draft.push_token(*tok);
+            }
+        }
+        draft
+    }
+}
+
+/// Accept or reject the `proposals` pushed onto `seq` given the target `logits` of its new
+/// positions, then remove them from `seq` again and roll back its KV cache.
+fn verify(seq: &mut Sequence, logits: &Tensor, proposals: &[Proposal]) -> Result<Vec<Logprobs>> {
+    let orig_len = seq.len() - proposals.len();
+    // The last rows hold the predictions for the positions of the proposals and the one after.
+    let first_row = logits.dim(0)? - (proposals.len() + 1);
+
+    let mut accepted = Vec::with_capacity(proposals.len() + 1);
+    for i in 0..=proposals.len() {
+        let logprobs = sampling_logprobs(seq, &logits.i(first_row + i)?, orig_len + i)?;
+        let target = seq.sampler().distribution(&logprobs);
+        let Some((tok, draft)) = proposals.get(i) else {
+            // Every proposal was accepted, so the target model gets to add one more token.
+            let tok = seq.sampler().pick(&target)?;
+            accepted.push(seq.sampler().output(&logprobs, tok)?);
+            break;
+        };
+        let draft = draft.iter().copied().collect::<HashMap<_, _>>();
+        let prob_of =
+            |distribution: &HashMap<u32, f32>, tok| *distribution.get(&tok).unwrap_or(&0.);
+        let ratio = target
+            .iter()
+            .find(|(t, _)| t == tok)
+            .map_or(0., |(_, prob)| *prob)
+            / prob_of(&draft, *tok);
+        if seq.sampler().random() < ratio {
+            seq.sampler().observe(&target, *tok);
+            accepted.push(seq.sampler().output(&logprobs, *tok)?);
+            continue;
+        }
+        let residual = target
+            .iter()
+            .map(|(tok, prob)| (*tok, prob - prob_of(&draft, *tok)))
+            .filter(|(_, prob)| *prob > 0.)
+            .collect::<Vec<_>>();
+        let tok = if residual.is_empty() {
+            seq.sampler().pick(&target)?
+        } else {
+            seq.sampler().pick(&residual)?
+        };
+        accepted.push(seq.sampler().output(&logprobs, tok)?);
+        break;
+    }
+
+    // The cache keeps the states of the accepted proposals. The last added token is run in the
+    // next step, like any sampled token.
+    seq.truncate_tokens(orig_len);
+    seq.cache().truncate(orig_len + accepted.len() - 1);
+    Ok(accepted)
+}
+
+/// The logprobs `seq`'s sampler would sample from after its first `len` tokens.
+fn sampling_logprobs(seq: &mut Sequence, logits: &Tensor, len: usize) -> Result<Vec<f32>> {
+    let mut logits = logits
+        .flatten_all()?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    seq.process_logits(&mut logits, len)?;
+    Ok(seq.sampler().logprobs(&logits))
+}
+
+/// The up to `num_tokens` tokens which followed the last earlier occurrence of the longest suffix
+/// of `tokens` of at most `max_ngram` tokens.
+fn lookup_ngram(tokens: &[u32], max_ngram: usize, num_tokens: usize) -> Vec<u32> {
+    for n in (1..=max_ngram.min(tokens.len().saturating_sub(1))).rev() {
+        let suffix = &tokens[tokens.len() - n..];
+        let found = tokens[..tokens.len() - 1]
+            .windows(n)
+            .rposition(|window| window == suffix);
+        if let Some(start) = found {
+            let next = start + n;
+            return tokens[next..(next + num_tokens).min(tokens.len())].to_vec();
+        }
+    }
+    Vec::new()
+}
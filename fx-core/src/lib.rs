@@ -6,7 +6,7 @@ use std::{
     },
     thread,
 };
-use engine::Engine;
+use engine::{Engine, Speculative};
 use pipeline::Pipeline;
 
 mod models;
@@ -16,9 +16,28 @@ mod scheduler;
 mod response;
 mod request;
 mod engine;
+mod sampler;
+mod constraints;
+mod logits_processors;
+mod stopping_criteria;
 
-pub use pipeline::{Loader, MistralLoader, MistralSpecificConfig, TokenSource};
-use request::Request;
+pub use constraints::{Constraint, ConstraintError};
+pub use engine::Drafter;
+pub use logits_processors::{
+    FrequencyPenalty, LogitBias, LogitsContext, LogitsProcessor, MinTokens, RepeatPenalty,
+};
+pub use pipeline::{GenerationConfig, Loader, MistralLoader, MistralSpecificConfig, TokenSource};
+pub use request::{
+    BeamSearchParams, MirostatParams, Request, SamplingParams, SamplingParamsError, StopReason,
+};
+pub use response::{
+    Beam, Choice, Completion, CompletionChunk, FinishReason, Response, TokenLogprob, TopLogprob,
+    Usage,
+};
+pub use stopping_criteria::{
+    CancelFlag, EosToken, MaxTokens, StopRegex, StopTokens, StoppingContext, StoppingCriterion,
+    Timeout,
+};
 
 pub struct FxServ {
     sender: Sender<Request>
@@ -32,13 +51,33 @@ impl FxServ {
         });
 
         thread::spawn(move || {
-            let mut engine = Engine::new(rx, pipeline);
+            let mut engine = Engine::new(rx, pipeline, None);
             engine.run();
         });
 
         this
     }
 
+    /// Like [`FxServ::new`], but decode with speculative decoding: `drafter` proposes up to
+    /// `num_draft_tokens` tokens at a time, which `pipeline` verifies.
+    pub fn new_speculative(
+        pipeline: Box<Mutex<dyn Pipeline>>,
+        drafter: Drafter,
+        num_draft_tokens: usize,
+    ) -> anyhow::Result<Arc<Self>> {
+        Speculative::validate(&*pipeline, &drafter, num_draft_tokens)?;
+        let (tx, rx) = channel();
+        let this = Arc::new(Self { sender: tx });
+
+        thread::spawn(move || {
+            let speculative = Speculative::new(drafter, num_draft_tokens);
+            let mut engine = Engine::new(rx, pipeline, Some(speculative));
+            engine.run();
+        });
+
+        Ok(this)
+    }
+
     pub fn get_sender(&self) -> Sender<Request> {
         self.sender.clone()
     }
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::Result;
+
+use crate::request::SamplingParams;
+
+/// The tokens of the sequence whose logits are being processed.
+pub struct LogitsContext<'a> {
+    tokens: &'a [u32],
+    prompt_len: usize,
+}
+
+impl<'a> LogitsContext<'a> {
+    pub(crate) fn new(tokens: &'a [u32], prompt_len: usize) -> Self {
+        Self { tokens, prompt_len }
+    }
+
+    /// All tokens so far, including the prompt.
+    pub fn tokens(&self) -> &[u32] {
+        self.tokens
+    }
+
+    pub fn prompt_tokens(&self) -> &[u32] {
+        &self.tokens[..self.prompt_len.min(self.tokens.len())]
+    }
+
+    /// The generated tokens, excluding the prompt.
+    pub fn completion_tokens(&self) -> &[u32] {
+        &self.tokens[self.prompt_len.min(self.tokens.len())..]
+    }
+
+    /// Number of times each token was generated.
+    pub fn token_counts(&self) -> HashMap<u32, usize> {
+        let mut counts = HashMap::new();
+        for tok in self.completion_tokens() {
+            *counts.entry(*tok).or_default() += 1;
+        }
+        counts
+    }
+}
+
+/// A transformation of the logits of a sequence before sampling, such as a penalty or a bias.
+///
+/// Each request runs an ordered chain of processors: those built from its [`SamplingParams`]
+/// (repeat penalty, frequency and presence penalties, logit bias, `min_tokens`), then
+/// [`SamplingParams::logits_processors`], then the mask of its constraint if there is one. The
+/// processors are shared by all sequences of the request, so any state which depends on the
+/// sequence must be derived from the [`LogitsContext`].
+pub trait LogitsProcessor: Send + Sync {
+    /// Transform `logits`, which has one entry per vocab token. Setting a logit to negative
+    /// infinity prevents the token from being sampled.
+    fn process(&self, logits: &mut [f32], ctx: &LogitsContext) -> Result<()>;
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: Fn(&mut [f32], &LogitsContext) -> Result<()> + Send + Sync,
+{
+    fn process(&self, logits: &mut [f32], ctx: &LogitsContext) -> Result<()> {
+        self(logits, ctx)
+    }
+}
+
+impl fmt::Debug for dyn LogitsProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogitsProcessor")
+    }
+}
+
+/// Divide the positive logits and multiply the negative ones of tokens in the last `last_n`
+/// tokens (or the whole sequence, including the prompt) by `penalty`.
+#[derive(Clone, Debug)]
+pub struct RepeatPenalty {
+    pub penalty: f32,
+    pub last_n: Option<usize>,
+}
+
+impl LogitsProcessor for RepeatPenalty {
+    fn process(&self, logits: &mut [f32], ctx: &LogitsContext) -> Result<()> {
+        let context = ctx.tokens();
+        let start_at = context
+            .len()
+            .saturating_sub(self.last_n.unwrap_or(context.len()));
+        let mut seen = context[start_at..].to_vec();
+        seen.sort_unstable();
+        seen.dedup();
+        for tok in seen {
+            if let Some(logit) = logits.get_mut(tok as usize) {
+                if *logit >= 0. {
+                    *logit /= self.penalty;
+                } else {
+                    *logit *= self.penalty;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// OpenAI-style penalties: subtract `frequency_penalty` from the logit of a token once per time it
+/// was generated, and `presence_penalty` if it was generated at all.
+#[derive(Clone, Debug)]
+pub struct FrequencyPenalty {
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+}
+
+impl LogitsProcessor for FrequencyPenalty {
+    fn process(&self, logits: &mut [f32], ctx: &LogitsContext) -> Result<()> {
+        for (tok, count) in ctx.token_counts() {
+            if let Some(logit) = logits.get_mut(tok as usize) {
+                *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add each token's bias to its logit. A bias of -100 bans the token.
+#[derive(Clone, Debug)]
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, logits: &mut [f32], _ctx: &LogitsContext) -> Result<()> {
+        for (tok, bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(*tok as usize) {
+                *logit = if *bias <= -100. {
+                    f32::NEG_INFINITY
+                } else {
+                    *logit + bias
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ban tokens until `min_tokens` tokens were generated.
+#[derive(Clone, Debug)]
+pub struct MinTokens {
+    pub min_tokens: usize,
+    pub banned: Vec<u32>,
+}
+
+impl LogitsProcessor for MinTokens {
+    fn process(&self, logits: &mut [f32], ctx: &LogitsContext) -> Result<()> {
+        if ctx.completion_tokens().len() >= self.min_tokens {
+            return Ok(());
+        }
+        for tok in &self.banned {
+            if let Some(logit) = logits.get_mut(*tok as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The processors of a request for a model with EOS tokens `eos_toks`, in the order they run
+/// (before the constraint mask).
+pub(crate) fn chain(params: &SamplingParams, eos_toks: &[u32]) -> Vec<Arc<dyn LogitsProcessor>> {
+    let mut chain: Vec<Arc<dyn LogitsProcessor>> = Vec::new();
+    if let Some(penalty) = params.repeat_penalty {
+        chain.push(Arc::new(RepeatPenalty {
+            penalty,
+            last_n: params.repeat_last_n,
+        }));
+    }
+    if params.frequency_penalty.is_some() || params.presence_penalty.is_some() {
+        chain.push(Arc::new(FrequencyPenalty {
+            frequency_penalty: params.frequency_penalty.unwrap_or(0.),
+            presence_penalty: params.presence_penalty.unwrap_or(0.),
+        }));
+    }
+    if let Some(logit_bias) = &params.logit_bias {
+        chain.push(Arc::new(LogitBias(logit_bias.clone())));
+    }
+    if params.min_tokens > 0 {
+        let mut banned = params.stop_toks.clone().unwrap_or_default();
+        if !params.ignore_eos {
+            banned.extend_from_slice(eos_toks);
+        }
+        chain.push(Arc::new(MinTokens {
+            min_tokens: params.min_tokens,
+            banned,
+        }));
+    }
+    chain.extend(params.logits_processors.iter().cloned());
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use anyhow::Result;
+
+    use super::{
+        chain, FrequencyPenalty, LogitBias, LogitsContext, LogitsProcessor, MinTokens,
+        RepeatPenalty,
+    };
+    use crate::request::SamplingParams;
+
+    const LOGITS: [f32; 4] = [1., -1., 2., 0.5];
+
+    /// The logits after `processor`, given the `prompt` and the `completion` generated so far.
+    fn process(processor: &dyn LogitsProcessor, prompt: &[u32], completion: &[u32]) -> Vec<f32> {
+        let tokens = [prompt, completion].concat();
+        let mut logits = LOGITS.to_vec();
+        processor
+            .process(&mut logits, &LogitsContext::new(&tokens, prompt.len()))
+            .unwrap();
+        logits
+    }
+
+    #[test]
+    fn logit_bias_is_added_and_bans_at_minus_100() {
+        let bias = LogitBias(HashMap::from([(0, 1.5), (2, -100.), (7, 1.)]));
+        assert_eq!(
+            process(&bias, &[], &[]),
+            vec![2.5, -1., f32::NEG_INFINITY, 0.5]
+        );
+    }
+
+    #[test]
+    fn frequency_and_presence_penalties_count_generated_tokens() {
+        let penalty = FrequencyPenalty {
+            frequency_penalty: 0.5,
+            presence_penalty: 1.,
+        };
+        // Only the completion is penalized, not the prompt.
+        assert_eq!(process(&penalty, &[0], &[2, 2, 1]), vec![1., -2.5, 0., 0.5]);
+    }
+
+    #[test]
+    fn repeat_penalty_applies_to_the_last_n_tokens() {
+        let penalty = |last_n| RepeatPenalty {
+            penalty: 2.,
+            last_n,
+        };
+        assert_eq!(
+            process(&penalty(None), &[0, 1], &[3]),
+            vec![0.5, -2., 2., 0.25]
+        );
+        assert_eq!(
+            process(&penalty(Some(2)), &[0, 1], &[3]),
+            vec![1., -2., 2., 0.25]
+        );
+    }
+
+    #[test]
+    fn custom_processors_run_after_the_built_in_ones() {
+        let scale = |logits: &mut [f32], _: &LogitsContext| -> Result<()> {
+            logits[0] *= 10.;
+            Ok(())
+        };
+        let params = SamplingParams {
+            logit_bias: Some(HashMap::from([(0, 1.)])),
+            logits_processors: vec![Arc::new(scale)],
+            ..Default::default()
+        };
+        let mut logits = LOGITS.to_vec();
+        for processor in chain(&params, &[]) {
+            processor
+                .process(&mut logits, &LogitsContext::new(&[], 0))
+                .unwrap();
+        }
+        assert_eq!(logits, vec![20., -1., 2., 0.5]);
+    }
+
+    #[test]
+    fn min_tokens_bans_tokens_until_enough_were_generated() {
+        let min_tokens = MinTokens {
+            min_tokens: 2,
+            banned: vec![0, 3],
+        };
+        assert_eq!(
+            process(&min_tokens, &[1], &[2]),
+            vec![f32::NEG_INFINITY, -1., 2., f32::NEG_INFINITY]
+        );
+        assert_eq!(process(&min_tokens, &[1], &[2, 2]), LOGITS.to_vec());
+    }
+}
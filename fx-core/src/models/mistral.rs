@@ -4,7 +4,7 @@ use candle_nn::{Activation, VarBuilder};
 use candle_transformers::models::with_tracing::{linear_no_bias, Linear};
 use std::{iter::zip, sync::Arc};
 
-use super::Cache;
+use super::{Cache, KvCache};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
@@ -77,28 +77,22 @@ impl RotaryEmbedding {
         })
     }
 
+    /// Rotate the `(1, num_heads, seq_len, head_dim)` queries and keys of one sequence, whose first
+    /// position is `seqlen_offset`.
     fn apply_rotary_emb_qkv(
         &self,
         q: &Tensor,
         k: &Tensor,
-        seqlen_offsets: &[usize],
+        seqlen_offset: usize,
     ) -> Result<(Tensor, Tensor)> {
-        let (b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let mut q_embeds = Vec::with_capacity(b_sz);
-        let mut k_embeds = Vec::with_capacity(b_sz);
-        for (b, seqlen_offset) in zip(0..b_sz, seqlen_offsets) {
-            let q_b = q.i(b)?.unsqueeze(0)?;
-            let k_b = k.i(b)?.unsqueeze(0)?;
-            let cos = self.cos.narrow(0, *seqlen_offset, seq_len)?;
-            let sin = self.sin.narrow(0, *seqlen_offset, seq_len)?;
-            let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-            let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-            let q_embed = (q_b.broadcast_mul(&cos)? + rotate_half(&q_b)?.broadcast_mul(&sin))?;
-            let k_embed = (k_b.broadcast_mul(&cos)? + rotate_half(&k_b)?.broadcast_mul(&sin))?;
-            q_embeds.push(q_embed);
-            k_embeds.push(k_embed);
-        }
-        Ok((Tensor::cat(&q_embeds, 0)?, Tensor::cat(&k_embeds, 0)?))
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
+        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
+        Ok((q_embed, k_embed))
     }
 }
 
@@ -192,24 +186,17 @@ impl Attention {
         })
     }
 
-    fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
-        let n_rep = self.num_kv_groups;
-        if n_rep == 1 {
-            Ok(xs)
-        } else {
-            let (b_sz, num_kv_heads, seq_len, head_dim) = xs.dims4()?;
-            xs.unsqueeze(2)?
-                .expand((b_sz, num_kv_heads, n_rep, seq_len, head_dim))?
-                .reshape((b_sz, num_kv_heads * n_rep, seq_len, head_dim))
-        }
-    }
-
+    /// Attention is computed separately for each sequence against views of the segments of its own
+    /// KV cache, so no cache data is copied to form a batch. Only the first `context_lens[b]`
+    /// positions of sequence `b` are real tokens, the rest is padding which is neither cached nor
+    /// attended.
     fn forward(
         &mut self,
         xs: &Tensor,
-        attention_mask: Option<&Tensor>,
+        attention_masks: &[Option<Tensor>],
         seqlen_offsets: &[usize],
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        context_lens: &[usize],
+        kv_caches: &mut [&mut KvCache],
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -227,47 +214,93 @@ impl Attention {
             .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let (query_states, key_states) =
-            self.rotary_emb
-                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offsets)?;
-
-        let (key_states, value_states) = match &*kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        *kv_cache = Some((key_states.clone(), value_states.clone()));
-
-        let key_states = self.repeat_kv(key_states)?;
-        let value_states = self.repeat_kv(value_states)?;
-
-        let attn_output = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = query_states.transpose(1, 2)?;
-            let k = key_states.transpose(1, 2)?;
-            let v = value_states.transpose(1, 2)?;
-            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
-        } else {
-            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
-            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
-
-            let attn_weights = match attention_mask {
-                None => attn_weights,
-                Some(mask) => attn_weights.broadcast_add(mask)?,
+        let mut attn_outputs = Vec::with_capacity(b_sz);
+        for (b, (((kv_cache, seqlen_offset), context_len), attention_mask)) in zip(
+            zip(zip(kv_caches.iter_mut(), seqlen_offsets), context_lens),
+            attention_masks,
+        )
+        .enumerate()
+        {
+            let q = query_states
+                .i(b)?
+                .unsqueeze(0)?
+                .narrow(2, 0, *context_len)?;
+            let k = key_states.i(b)?.unsqueeze(0)?.narrow(2, 0, *context_len)?;
+            let v = value_states
+                .i(b)?
+                .unsqueeze(0)?
+                .narrow(2, 0, *context_len)?;
+            // Only the real tokens are rotated, as the positions of the padding may be past the last
+            // position embedding.
+            let (q, k) = self
+                .rotary_emb
+                .apply_rotary_emb_qkv(&q, &k, *seqlen_offset)?;
+
+            let (ks, vs): (Vec<_>, Vec<_>) = kv_cache.append(&k, &v)?.into_iter().unzip();
+
+            let attn_output = if self.use_flash_attn {
+                // flash-attn expects (b_sz, seq_len, nheads, head_dim) and handles the key/value
+                // head groups itself, but needs the cache in one piece.
+                let q = q.transpose(1, 2)?;
+                let k = Tensor::cat(&ks, 2)?.transpose(1, 2)?;
+                let v = Tensor::cat(&vs, 2)?.transpose(1, 2)?;
+                let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+                flash_attn(&q, &k, &v, softmax_scale, *context_len > 1)?.transpose(1, 2)?
+            } else {
+                // The query heads sharing a key/value head are stacked along the sequence
+                // dimension, so the cache is attended without repeating it for each of them.
+                let n_rep = self.num_kv_groups;
+                let q = q.contiguous()?.reshape((
+                    1,
+                    self.num_kv_heads,
+                    n_rep * context_len,
+                    self.head_dim,
+                ))?;
+                let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+                // The weights of every cache segment are softmaxed together, so that the segments
+                // are attended as one sequence without being concatenated.
+                let attn_weights = ks
+                    .iter()
+                    .map(|k| q.matmul(&k.transpose(2, 3)?))
+                    .collect::<Result<Vec<_>>>()?;
+                let attn_weights = (Tensor::cat(&attn_weights, 3)? * scale)?;
+                let kv_len = attn_weights.dim(3)?;
+
+                let attn_weights = match attention_mask {
+                    None => attn_weights,
+                    Some(mask) => attn_weights
+                        .reshape((1, self.num_kv_heads, n_rep, *context_len, kv_len))?
+                        .broadcast_add(&mask.unsqueeze(1)?)?
+                        .reshape((1, self.num_kv_heads, n_rep * context_len, kv_len))?,
+                };
+                let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+                let mut attn_output = None;
+                let mut offset = 0;
+                for v in &vs {
+                    let len = v.dim(2)?;
+                    let output = attn_weights
+                        .narrow(3, offset, len)?
+                        .contiguous()?
+                        .matmul(v)?;
+                    attn_output = Some(match attn_output {
+                        Some(acc) => (acc + output)?,
+                        None => output,
+                    });
+                    offset += len;
+                }
+                // NOTE Unwrap reasoning: The cache holds at least the states which were just
+                // appended, so it has a segment.
+                attn_output
+                    .unwrap()
+                    .reshape((1, self.num_heads, *context_len, self.head_dim))?
             };
-            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-            attn_weights.matmul(&value_states)?
-        };
-        attn_output
+            attn_outputs.push(attn_output.pad_with_zeros(2, 0, q_len - context_len)?);
+        }
+        Tensor::cat(&attn_outputs, 0)?
             .transpose(1, 2)?
             .reshape((b_sz, q_len, self.hidden_size))?
             .apply(&self.o_proj)
     }
-
 }
 
 #[derive(Debug, Clone)]
@@ -300,15 +333,20 @@ impl DecoderLayer {
     fn forward(
         &mut self,
         xs: &Tensor,
-        attention_mask: Option<&Tensor>,
+        attention_masks: &[Option<Tensor>],
         seqlen_offsets: &[usize],
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        context_lens: &[usize],
+        kv_caches: &mut [&mut KvCache],
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self
-            .self_attn
-            .forward(&xs, attention_mask, seqlen_offsets, kv_cache)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_masks,
+            seqlen_offsets,
+            context_lens,
+            kv_caches,
+        )?;
         let xs = (xs + residual)?;
         let residual = &xs;
         let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
@@ -326,7 +364,6 @@ pub struct Model {
     sliding_window: usize,
     device: Device,
     dtype: DType,
-    cache: Cache,
 }
 
 impl Model {
@@ -351,83 +388,100 @@ impl Model {
             sliding_window: cfg.sliding_window,
             device: vb.device().clone(),
             dtype: vb.dtype(),
-            cache: Cache::new(cfg.num_hidden_layers),
         })
     }
 
-    fn prepare_decoder_attention_mask(
-        &self,
-        b_size: usize,
-        tgt_len: usize,
-        seqlen_offset: usize,
-    ) -> Result<Tensor> {
-        // Sliding window mask?
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + self.sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+    pub fn device(&self) -> &Device {
+        &self.device
     }
 
-    fn calculate_past_kv_len(&mut self, seq_len: usize) -> Result<usize> {
-        let cache = self.cache.lock();
-        let kv_cache_1 = cache.first().unwrap();
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        if k_cache_1.dims()[0] <= seq_len {
-            Ok(0)
-        } else {
-            let indexed = k_cache_1.i(seq_len)?;
-            let dims = indexed.dims();
-            Ok(dims[dims.len() - 2])
+    pub fn num_hidden_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Build the causal sliding window mask of each sequence, shaped
+    /// `(1, 1, context_len, past + context_len)`. A single new token which sees its whole past
+    /// needs no mask.
+    fn prepare_decoder_attention_masks(
+        &self,
+        seqlen_offsets: &[usize],
+        context_lens: &[usize],
+    ) -> Result<Vec<Option<Tensor>>> {
+        let mut masks = Vec::with_capacity(seqlen_offsets.len());
+        for (past, tgt_len) in zip(seqlen_offsets, context_lens) {
+            let src_len = past + tgt_len;
+            if *tgt_len <= 1 && src_len <= self.sliding_window + 1 {
+                masks.push(None);
+                continue;
+            }
+            let mask: Vec<_> = (0..*tgt_len)
+                .flat_map(|i| {
+                    (0..src_len).map(move |j| {
+                        // Key at position `j`, query at position `past + i`.
+                        if past + i < j || j + self.sliding_window < past + i {
+                            f32::NEG_INFINITY
+                        } else {
+                            0.
+                        }
+                    })
+                })
+                .collect();
+            let mask = Tensor::from_vec(mask, (1, 1, *tgt_len, src_len), &self.device)?
+                .to_dtype(self.dtype)?;
+            masks.push(Some(mask));
         }
+        Ok(masks)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offsets: &[usize]) -> Result<Tensor> {
-        let (b_size, seq_len) = input_ids.dims2()?;
-        if seqlen_offsets.len() > b_size {
-            candle_core::bail!("Expected seqlen offsets have length equal to batch size.")
+    /// `seqlen_offsets` holds the number of already cached tokens for each sequence in the batch,
+    /// `context_lens` the number of real (unpadded) new tokens in `input_ids`, and `caches` the KV
+    /// cache of each sequence, which is read and extended in place.
+    ///
+    /// Returns the logits of the last real token of each sequence, shaped `(b_size, 1, vocab)`, or
+    /// if `all_logits` is set, those of every position, shaped `(b_size, seq_len, vocab)`. The
+    /// logits of padding positions are meaningless.
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        context_lens: &[usize],
+        caches: &[Cache],
+        all_logits: bool,
+    ) -> Result<Tensor> {
+        let (b_size, _seq_len) = input_ids.dims2()?;
+        if seqlen_offsets.len() != b_size || context_lens.len() != b_size || caches.len() != b_size
+        {
+            candle_core::bail!(
+                "Expected seqlen offsets, context lens and caches have length equal to batch size."
+            )
         }
-        let past_key_values_length = self.calculate_past_kv_len(seq_len)?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask =
-                self.prepare_decoder_attention_mask(b_size, seq_len, past_key_values_length)?;
-            Some(mask)
-        };
+        let attention_masks = self.prepare_decoder_attention_masks(seqlen_offsets, context_lens)?;
 
         let mut xs = self.embed_tokens.forward(input_ids)?;
-        let mut cache = self.cache.lock();
+        let mut caches = caches.iter().map(|cache| cache.lock()).collect::<Vec<_>>();
         for (i, layer) in self.layers.iter_mut().enumerate() {
+            let mut kv_caches = caches
+                .iter_mut()
+                .map(|cache| cache.get_mut(i).unwrap())
+                .collect::<Vec<_>>();
             xs = layer.forward(
-                &xs, 
-                attention_mask.as_ref(), 
-                seqlen_offsets, 
-                cache.get_mut(i).unwrap(),
+                &xs,
+                &attention_masks,
+                seqlen_offsets,
+                context_lens,
+                &mut kv_caches,
             )?
-        };
+        }
 
-        xs.narrow(1, seq_len - 1, 1)?
+        if all_logits {
+            return xs.apply(&self.norm)?.apply(&self.lm_head);
+        }
+        let mut last_xs = Vec::with_capacity(b_size);
+        for (b, context_len) in context_lens.iter().enumerate() {
+            last_xs.push(xs.i(b)?.narrow(0, context_len - 1, 1)?.unsqueeze(0)?);
+        }
+        Tensor::cat(&last_xs, 0)?
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
-
 }
@@ -1,26 +1,375 @@
 pub(crate) mod mistral;
 
-use std::sync::{
-    Arc, Mutex, MutexGuard
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
 };
-use candle_core::Tensor;
+use candle_core::{Result, Tensor};
 use crate::get_mut_arcmutex;
 
-pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
+pub type LayerCaches = Vec<KvCache>;
+
+/// A run of cached positions: `(b_sz, num_kv_heads, capacity, head_dim)` K and V buffers of which
+/// the first `len` positions along the sequence dimension are valid.
+#[derive(Debug, Clone)]
+struct KvSegment {
+    k: Tensor,
+    v: Tensor,
+    len: usize,
+}
+
+impl KvSegment {
+    fn current(&self) -> Result<(Tensor, Tensor)> {
+        Ok((
+            self.k.narrow(2, 0, self.len)?,
+            self.v.narrow(2, 0, self.len)?,
+        ))
+    }
+}
+
+/// The KV cache of one layer, made of segments which are attended in order.
+///
+/// New states are written in place into the tail segment, whose buffers only grow
+/// (geometrically) once they are full. Forking the cache freezes the tail into a read-only segment
+/// which is shared by both caches, so forks of a sequence share the KV of their common prefix and
+/// only store the positions they generated since.
+#[derive(Debug, Default)]
+pub struct KvCache {
+    /// Segments shared with forks of this cache, which are never written to.
+    shared: Vec<KvSegment>,
+    /// The positions appended since the cache was last forked.
+    tail: Option<KvSegment>,
+}
+
+impl KvCache {
+    /// Minimum number of positions to allocate at once.
+    const CHUNK_SIZE: usize = 256;
+    /// Minimum number of positions to allocate for the first tail after a fork, which is often
+    /// frozen by the next fork after a single append (such as for a beam).
+    const FORKED_CHUNK_SIZE: usize = 16;
+    /// Number of shared segments above which they are merged into one, so that attending a cache
+    /// which is forked at every step (such as a beam) does not take ever more matmuls.
+    const MAX_SHARED_SEGMENTS: usize = 8;
+
+    /// Create a cache holding exactly the given `(b_sz, num_kv_heads, seq_len, head_dim)` states.
+    pub(crate) fn from_tensors(k: Tensor, v: Tensor) -> Result<Self> {
+        let len = k.dim(2)?;
+        Ok(Self {
+            shared: Vec::new(),
+            tail: Some(KvSegment {
+                k: k.contiguous()?,
+                v: v.contiguous()?,
+                len,
+            }),
+        })
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &KvSegment> {
+        self.shared.iter().chain(&self.tail)
+    }
+
+    /// Number of cached positions.
+    pub(crate) fn len(&self) -> usize {
+        self.segments().map(|segment| segment.len).sum()
+    }
+
+    /// Discard all but the first `len` cached positions. The buffers are kept, so the discarded
+    /// positions of the tail are simply overwritten by the next append.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let mut remaining = len;
+        for segment in self.shared.iter_mut().chain(&mut self.tail) {
+            segment.len = segment.len.min(remaining);
+            remaining -= segment.len;
+        }
+        self.shared.retain(|segment| segment.len > 0);
+    }
+
+    /// The valid part of the cache concatenated into one tensor each for K and V, or `None` if
+    /// nothing has been cached yet.
+    pub(crate) fn current(&self) -> Result<Option<(Tensor, Tensor)>> {
+        let (ks, vs): (Vec<_>, Vec<_>) = self
+            .segments()
+            .filter(|segment| segment.len > 0)
+            .map(KvSegment::current)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        if ks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((Tensor::cat(&ks, 2)?, Tensor::cat(&vs, 2)?)))
+    }
+
+    /// Write `k` and `v` after the cached positions and return the valid part of every segment of
+    /// the cache, including the new states, in order.
+    pub(crate) fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<Vec<(Tensor, Tensor)>> {
+        let (len, capacity) = match &self.tail {
+            Some(tail) => (tail.len, tail.k.dim(2)?),
+            None => (0, 0),
+        };
+        let new_len = len + k.dim(2)?;
+        if new_len > capacity {
+            let chunk_size = if self.tail.is_none() && !self.shared.is_empty() {
+                Self::FORKED_CHUNK_SIZE
+            } else {
+                Self::CHUNK_SIZE
+            };
+            let capacity = new_len.max(2 * capacity).next_multiple_of(chunk_size);
+            let old = self.tail.as_ref();
+            self.tail = Some(KvSegment {
+                k: Self::grow(old.map(|t| &t.k), k, len, capacity)?,
+                v: Self::grow(old.map(|t| &t.v), v, len, capacity)?,
+                len,
+            });
+        }
+        // NOTE Unwrap reasoning: The tail was allocated above if it was missing.
+        let tail = self.tail.as_mut().unwrap();
+        tail.k.slice_set(&k.contiguous()?, 2, len)?;
+        tail.v.slice_set(&v.contiguous()?, 2, len)?;
+        tail.len = new_len;
+        self.segments()
+            .filter(|segment| segment.len > 0)
+            .map(KvSegment::current)
+            .collect()
+    }
+
+    /// Create a cache holding the same positions as this one. The tail of this cache is frozen
+    /// into a shared segment, so both caches append to tails of their own from now on.
+    pub(crate) fn fork(&mut self) -> Result<Self> {
+        if let Some(tail) = self.tail.take().filter(|tail| tail.len > 0) {
+            // Only the valid positions are kept, as a shared segment never grows.
+            let (k, v) = tail.current()?;
+            self.shared.push(KvSegment {
+                k: k.contiguous()?,
+                v: v.contiguous()?,
+                len: tail.len,
+            });
+        }
+        if self.shared.len() > Self::MAX_SHARED_SEGMENTS {
+            // NOTE Unwrap reasoning: There are shared segments, so something is cached.
+            let (k, v) = self.current()?.unwrap();
+            let len = k.dim(2)?;
+            self.shared = vec![KvSegment { k, v, len }];
+        }
+        Ok(Self {
+            shared: self.shared.clone(),
+            tail: None,
+        })
+    }
+
+    /// Allocate a buffer of `capacity` positions shaped like `like`, holding the first `len`
+    /// positions of `old`.
+    fn grow(old: Option<&Tensor>, like: &Tensor, len: usize, capacity: usize) -> Result<Tensor> {
+        let (b_sz, num_kv_heads, _, head_dim) = like.dims4()?;
+        let buf = Tensor::zeros(
+            (b_sz, num_kv_heads, capacity, head_dim),
+            like.dtype(),
+            like.device(),
+        )?;
+        if let Some(old) = old.filter(|_| len > 0) {
+            buf.slice_set(&old.narrow(2, 0, len)?.contiguous()?, 2, 0)?;
+        }
+        Ok(buf)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Cache {
-    cache: Arc<Mutex<Vec<Option<(Tensor, Tensor)>>>>,
+    cache: Arc<Mutex<LayerCaches>>,
 }
 
 impl Cache {
     pub(crate) fn new(len: usize) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(vec![None; len])),
+            cache: Arc::new(Mutex::new((0..len).map(|_| KvCache::default()).collect())),
         }
     }
 
     pub(crate) fn lock(&self) -> MutexGuard<'_, LayerCaches> {
         get_mut_arcmutex!(self.cache)
     }
-}
\ No newline at end of file
+
+    /// Number of positions cached in every layer.
+    pub(crate) fn len(&self) -> usize {
+        self.lock().first().map_or(0, KvCache::len)
+    }
+
+    /// Discard all but the first `len` cached positions of every layer, such as rejected
+    /// speculative tokens.
+    pub(crate) fn truncate(&self, len: usize) {
+        for layer in self.lock().iter_mut() {
+            layer.truncate(len);
+        }
+    }
+
+    /// The valid part of every cached layer, keyed by `layers.{i}.k` and `layers.{i}.v`. Layers
+    /// which have nothing cached are omitted.
+    pub(crate) fn tensors(&self) -> Result<HashMap<String, Tensor>> {
+        let mut tensors = HashMap::new();
+        for (i, layer) in self.lock().iter().enumerate() {
+            if let Some((k, v)) = layer.current()? {
+                tensors.insert(format!("layers.{i}.k"), k);
+                tensors.insert(format!("layers.{i}.v"), v);
+            }
+        }
+        Ok(tensors)
+    }
+
+    /// Rebuild a cache of `len` layers from tensors produced by [`Cache::tensors`].
+    pub(crate) fn from_tensors(tensors: &HashMap<String, Tensor>, len: usize) -> Result<Self> {
+        let mut layers = Vec::with_capacity(len);
+        for i in 0..len {
+            let layer = match (
+                tensors.get(&format!("layers.{i}.k")),
+                tensors.get(&format!("layers.{i}.v")),
+            ) {
+                (Some(k), Some(v)) => KvCache::from_tensors(k.clone(), v.clone())?,
+                (None, None) => KvCache::default(),
+                _ => candle_core::bail!("Layer {i} is missing either its K or V cache."),
+            };
+            layers.push(layer);
+        }
+        if tensors.keys().any(|name| {
+            name.strip_prefix("layers.")
+                .and_then(|rest| rest.split('.').next())
+                .and_then(|i| i.parse::<usize>().ok())
+                .is_some_and(|i| i >= len)
+        }) {
+            candle_core::bail!("Cache has more layers than the expected {len}.")
+        }
+        Ok(Self {
+            cache: Arc::new(Mutex::new(layers)),
+        })
+    }
+
+    /// Create an independent cache which shares the KV of every position cached so far with this
+    /// one. Both only store the positions appended from now on separately.
+    pub(crate) fn fork(&self) -> Result<Self> {
+        let layers = self
+            .lock()
+            .iter_mut()
+            .map(KvCache::fork)
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            cache: Arc::new(Mutex::new(layers)),
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::KvCache;
+
+    /// `len` distinct `(1, 2, len, 4)` K and V states.
+    fn states(len: usize, seed: f64) -> (Tensor, Tensor) {
+        let k = Tensor::arange(0u32, 8 * len as u32, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .affine(1., seed)
+            .unwrap()
+            .reshape((1, 2, len, 4))
+            .unwrap();
+        let v = k.neg().unwrap();
+        (k, v)
+    }
+
+    fn cat(states: &[&(Tensor, Tensor)]) -> (Tensor, Tensor) {
+        let ks = states.iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let vs = states.iter().map(|(_, v)| v).collect::<Vec<_>>();
+        (Tensor::cat(&ks, 2).unwrap(), Tensor::cat(&vs, 2).unwrap())
+    }
+
+    fn values(tensor: &Tensor) -> (Vec<usize>, Vec<f32>) {
+        (
+            tensor.dims().to_vec(),
+            tensor.flatten_all().unwrap().to_vec1().unwrap(),
+        )
+    }
+
+    fn assert_holds(cache: &KvCache, (k, v): &(Tensor, Tensor)) {
+        let (cached_k, cached_v) = cache.current().unwrap().unwrap();
+        assert_eq!(values(&cached_k), values(k));
+        assert_eq!(values(&cached_v), values(v));
+        assert_eq!(cache.len(), k.dim(2).unwrap());
+    }
+
+    #[test]
+    fn append_matches_concatenation() {
+        let mut cache = KvCache::default();
+        assert!(cache.current().unwrap().is_none());
+        let mut appended = Vec::new();
+        // Enough positions for the buffers to grow twice.
+        for (i, len) in [100, 1, 200, 1, 300].into_iter().enumerate() {
+            let (k, v) = states(len, 1000. * i as f64);
+            let segments = cache.append(&k, &v).unwrap();
+            appended.push((k, v));
+            let expected = cat(&appended.iter().collect::<Vec<_>>());
+            let returned = cat(&segments.iter().collect::<Vec<_>>());
+            assert_eq!(values(&returned.0), values(&expected.0));
+            assert_eq!(values(&returned.1), values(&expected.1));
+            assert_holds(&cache, &expected);
+        }
+    }
+
+    #[test]
+    fn forks_share_their_prefix_and_diverge() {
+        let mut cache = KvCache::default();
+        let prefix = states(5, 0.);
+        cache.append(&prefix.0, &prefix.1).unwrap();
+        let mut fork = cache.fork().unwrap();
+        assert_holds(&fork, &prefix);
+
+        let ours = states(3, 100.);
+        let theirs = states(2, 200.);
+        cache.append(&ours.0, &ours.1).unwrap();
+        fork.append(&theirs.0, &theirs.1).unwrap();
+        assert_holds(&cache, &cat(&[&prefix, &ours]));
+        assert_holds(&fork, &cat(&[&prefix, &theirs]));
+    }
+
+    #[test]
+    fn forking_at_every_step_matches_concatenation() {
+        let mut cache = KvCache::default();
+        let mut appended = Vec::new();
+        // More forks than shared segments are kept, so that they get merged.
+        for i in 0..3 * KvCache::MAX_SHARED_SEGMENTS {
+            let (k, v) = states(1 + i % 3, 100. * i as f64);
+            cache.append(&k, &v).unwrap();
+            appended.push((k, v));
+            let fork = cache.fork().unwrap();
+            assert!(cache.shared.len() <= KvCache::MAX_SHARED_SEGMENTS);
+            assert_holds(&fork, &cat(&appended.iter().collect::<Vec<_>>()));
+        }
+        assert_holds(&cache, &cat(&appended.iter().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn truncate_discards_positions_across_segments() {
+        let mut cache = KvCache::default();
+        let first = states(4, 0.);
+        let second = states(4, 100.);
+        cache.append(&first.0, &first.1).unwrap();
+        let _fork = cache.fork().unwrap();
+        cache.append(&second.0, &second.1).unwrap();
+
+        // Rejected positions of the tail are overwritten by the next append.
+        cache.truncate(6);
+        let third = states(3, 200.);
+        cache.append(&third.0, &third.1).unwrap();
+        let kept = (
+            second.0.narrow(2, 0, 2).unwrap(),
+            second.1.narrow(2, 0, 2).unwrap(),
+        );
+        assert_holds(&cache, &cat(&[&first, &kept, &third]));
+
+        // Truncating into a shared segment also empties the tail.
+        cache.truncate(2);
+        let kept = (
+            first.0.narrow(2, 0, 2).unwrap(),
+            first.1.narrow(2, 0, 2).unwrap(),
+        );
+        assert_holds(&cache, &kept);
+    }
+}
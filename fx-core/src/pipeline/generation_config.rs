@@ -0,0 +1,126 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::request::SamplingParams;
+
+/// The recommended sampling parameters a model repo ships in `generation_config.json`. Its EOS
+/// tokens are read along with those of the other configuration files.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GenerationConfig {
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub max_new_tokens: Option<usize>,
+    pub repetition_penalty: Option<f32>,
+}
+
+impl GenerationConfig {
+    pub(crate) fn from_file(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Fill the fields of `params` which are unset with these defaults. `do_sample: false` means
+    /// greedy decoding, in which case the other sampling defaults do not apply.
+    pub fn apply(&self, params: &mut SamplingParams) {
+        if self.do_sample == Some(false) {
+            params.temperature.get_or_insert(0.);
+        } else {
+            if params.temperature.is_none() {
+                params.temperature = self.temperature;
+            }
+            if params.top_k.is_none() {
+                // `transformers` disables top-k with 0.
+                params.top_k = self.top_k.filter(|top_k| *top_k > 0);
+            }
+            if params.top_p.is_none() {
+                params.top_p = self.top_p;
+            }
+        }
+        if params.max_tokens.is_none() {
+            params.max_tokens = self.max_new_tokens;
+        }
+        if params.repeat_penalty.is_none() {
+            params.repeat_penalty = self.repetition_penalty;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GenerationConfig;
+    use crate::request::SamplingParams;
+
+    fn config() -> GenerationConfig {
+        GenerationConfig {
+            do_sample: Some(true),
+            temperature: Some(0.6),
+            top_k: Some(50),
+            top_p: Some(0.9),
+            max_new_tokens: Some(256),
+            repetition_penalty: Some(1.1),
+        }
+    }
+
+    #[test]
+    fn fills_unset_fields() {
+        let mut params = SamplingParams::default();
+        config().apply(&mut params);
+        assert_eq!(params.temperature, Some(0.6));
+        assert_eq!(params.top_k, Some(50));
+        assert_eq!(params.top_p, Some(0.9));
+        assert_eq!(params.max_tokens, Some(256));
+        assert_eq!(params.repeat_penalty, Some(1.1));
+    }
+
+    #[test]
+    fn keeps_fields_the_request_set() {
+        let mut params = SamplingParams {
+            temperature: Some(1.),
+            top_k: Some(5),
+            top_p: Some(0.5),
+            max_tokens: Some(16),
+            repeat_penalty: Some(1.),
+            ..Default::default()
+        };
+        config().apply(&mut params);
+        assert_eq!(params.temperature, Some(1.));
+        assert_eq!(params.top_k, Some(5));
+        assert_eq!(params.top_p, Some(0.5));
+        assert_eq!(params.max_tokens, Some(16));
+        assert_eq!(params.repeat_penalty, Some(1.));
+    }
+
+    #[test]
+    fn greedy_config_skips_the_sampling_defaults() {
+        let greedy = GenerationConfig {
+            do_sample: Some(false),
+            ..config()
+        };
+        let mut params = SamplingParams::default();
+        greedy.apply(&mut params);
+        assert_eq!(params.temperature, Some(0.));
+        assert_eq!(params.top_k, None);
+        assert_eq!(params.top_p, None);
+
+        let mut params = SamplingParams {
+            temperature: Some(0.8),
+            ..Default::default()
+        };
+        greedy.apply(&mut params);
+        assert_eq!(params.temperature, Some(0.8));
+    }
+
+    #[test]
+    fn top_k_of_0_disables_top_k() {
+        let no_top_k = GenerationConfig {
+            top_k: Some(0),
+            ..config()
+        };
+        let mut params = SamplingParams::default();
+        no_top_k.apply(&mut params);
+        assert_eq!(params.top_k, None);
+    }
+}
@@ -1,18 +1,23 @@
 use std::{
+    cell::RefCell,
     iter::repeat,
-    sync::Mutex,
+    path::PathBuf,
+    rc::Rc,
+    sync::{Arc, Mutex},
 };
-use super::{Loader, ModelPaths, Pipeline, SimpleModelPaths, TokenSource};
+use super::{GenerationConfig, Loader, ModelPaths, Pipeline, SimpleModelPaths, TokenSource};
 use crate::{
+    deref_mut_refcell,
     models::mistral::{Config, Model},
     request::Sequence,
+    sampler::Logprobs,
     utils::{
-        dtype::get_dtype_from_torch_dtype, tokens::get_token,
+        dtype::get_dtype_from_torch_dtype, eos::get_eos_toks, tokens::get_token,
         varbuilder_utils::from_mmaped_safetensors,
     },
 };
 use anyhow::Result;
-use candle_core::{DType, Device, Tensor};
+use candle_core::{DType, Device, IndexOp, Tensor};
 use candle_nn::Activation;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use serde::Deserialize;
@@ -21,7 +26,10 @@ use tokenizers::Tokenizer;
 
 pub struct MistralPipeline {
     model: Model,
-    tokenizer: Tokenizer,
+    tokenizer: Arc<Tokenizer>,
+    vocab_size: usize,
+    eos_toks: Vec<u32>,
+    generation_config: GenerationConfig,
 }
 
 pub struct MistralLoader {
@@ -93,21 +101,34 @@ impl Loader for MistralLoader {
 
         let config_filename = api.get("config.json")?;
 
-        let mut filenames = vec![];
-        for rfilename in api
+        let siblings = api
             .info()?
             .siblings
-            .iter()
-            .map(|x| x.rfilename.clone())
-            .filter(|x| x.ends_with(".safetensors"))
-        {
-            let filename = api.get(&rfilename)?;
+            .into_iter()
+            .map(|x| x.rfilename)
+            .collect::<Vec<_>>();
+
+        // These are optional, older repos do not have them.
+        let get_optional = |rfilename: &str| -> Result<Option<PathBuf>> {
+            if !siblings.iter().any(|x| x == rfilename) {
+                return Ok(None);
+            }
+            Ok(Some(api.get(rfilename)?))
+        };
+        let generation_config_filename = get_optional("generation_config.json")?;
+        let tokenizer_config_filename = get_optional("tokenizer_config.json")?;
+
+        let mut filenames = vec![];
+        for rfilename in siblings.iter().filter(|x| x.ends_with(".safetensors")) {
+            let filename = api.get(rfilename)?;
             filenames.push(filename);
         }
 
         Ok(Box::new(SimpleModelPaths {
             tokenizer_filename,
             config_filename,
+            generation_config_filename,
+            tokenizer_config_filename,
             filenames,
         }))
     }
@@ -151,31 +172,60 @@ impl Loader for MistralLoader {
 
         let tokenizer = Tokenizer::from_file(paths.get_tokenizer_filename())
             .map_err(|e| TokenizerError::Error(e.to_string()))?;
+        let eos_toks = get_eos_toks(
+            paths.get_config_filename(),
+            paths.get_generation_config_filename().map(PathBuf::as_path),
+            paths.get_tokenizer_config_filename().map(PathBuf::as_path),
+            &tokenizer,
+        )?;
+        let generation_config = match paths.get_generation_config_filename() {
+            Some(path) => GenerationConfig::from_file(path)?,
+            None => GenerationConfig::default(),
+        };
 
-        Ok(Box::new(Mutex::new(MistralPipeline { model, tokenizer })))
+        Ok(Box::new(Mutex::new(MistralPipeline {
+            model,
+            tokenizer: Arc::new(tokenizer),
+            vocab_size: basic_config.vocab_size,
+            eos_toks,
+            generation_config,
+        })))
     }
 }
 
-impl Pipeline for MistralPipeline {
-    fn forward(&mut self, input_toks: Vec<&mut Sequence>) -> Result<Tensor> {
-        let max_len = input_toks.iter().map(|seq| seq.len()).max().unwrap();
+impl MistralPipeline {
+    /// Run the model over the tokens of each sequence which are not in its KV cache yet: the whole
+    /// prompt at prefill, then the last sampled token and any speculative tokens after it. Returns
+    /// the logits and the number of new tokens of each sequence.
+    fn run(
+        &mut self,
+        input_toks: &[Rc<RefCell<Sequence>>],
+        all_logits: bool,
+    ) -> Result<(Tensor, Vec<usize>)> {
         let padding_tok = 0;
-        // Pad each sequence by the padding token to the max len.
-        let mut seqs_tensors = Vec::new();
+        let mut ctxts = Vec::new();
         let mut seqlen_offsets = Vec::new();
+        let mut context_lens = Vec::new();
+        let mut caches = Vec::new();
 
-        for seq in input_toks.into_iter() {
-            let context_size = if *seq.gen_idx() > 0 {
-                1
-            } else {
-                seq.get_tokens().len()
-            };
-
-            let start_pos = seq.get_tokens().len().saturating_sub(context_size);
-            let mut ctxt = seq.get_tokens()[start_pos..].to_vec();
+        for seq in input_toks.iter() {
+            let mut seq = deref_mut_refcell!(seq);
+            // At least the last token is run, to get its logits.
+            let start_pos = seq.cache().len().min(seq.get_tokens().len() - 1);
+            seq.cache().truncate(start_pos);
+            let ctxt = seq.get_tokens()[start_pos..].to_vec();
             seqlen_offsets.push(start_pos);
+            context_lens.push(ctxt.len());
+            caches.push(seq.cache().clone());
             *seq.gen_idx() += 1;
 
+            ctxts.push(ctxt);
+        }
+
+        // Pad each sequence by the padding token to the max len.
+        let max_len = context_lens.iter().copied().max().unwrap();
+        let mut seqs_tensors = Vec::new();
+        for mut ctxt in ctxts {
             ctxt.extend(repeat(padding_tok).take(max_len - ctxt.len()));
 
             seqs_tensors.push(
@@ -187,7 +237,28 @@ impl Pipeline for MistralPipeline {
         }
         let input_ids = Tensor::cat(&seqs_tensors, 0).unwrap();
 
-        Ok(self.model.forward(&input_ids, todo!())?)
+        let logits = self.model.forward(
+            &input_ids,
+            &seqlen_offsets,
+            &context_lens,
+            &caches,
+            all_logits,
+        )?;
+        Ok((logits, context_lens))
+    }
+}
+
+impl Pipeline for MistralPipeline {
+    fn forward(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>) -> Result<Tensor> {
+        Ok(self.run(&input_toks, false)?.0)
+    }
+    fn forward_all(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>) -> Result<Vec<Tensor>> {
+        let (logits, context_lens) = self.run(&input_toks, true)?;
+        context_lens
+            .into_iter()
+            .enumerate()
+            .map(|(i, context_len)| Ok(logits.i(i)?.narrow(0, 0, context_len)?))
+            .collect()
     }
     fn tokenize_prompt(&self, prompt: &str) -> Result<Vec<u32>> {
         let encoding = self
@@ -197,34 +268,30 @@ impl Pipeline for MistralPipeline {
         Ok(encoding.get_ids().to_vec())
     }
     fn device(&self) -> &Device {
-        todo!()
+        self.model.device()
     }
     fn num_hidden_layers(&self) -> usize {
-        todo!()
+        self.model.num_hidden_layers()
     }
-    fn cache(&self) -> &crate::models::Cache {
-        todo!()
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
     }
     fn sample(&mut self, logits: Tensor, seq: Rc<RefCell<Sequence>>) -> Result<Logprobs> {
-        let logits = logits.squeeze(0).unwrap().to_dtype(DType::F32).unwrap();
-        let start_at = deref_refcell!(seq)
-            .get_toks()
-            .len()
-            .saturating_sub(self.config.repeat_last_n);
-        let ctxt = deref_refcell!(seq).get_toks()[start_at..].to_vec();
-
-        Ok(deref_mut_refcell!(seq)
-            .logits_processor()
-            .sample(&logits, Some(&ctxt))?)
+        let mut seq = deref_mut_refcell!(seq);
+        let mut logits = logits
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        seq.process_logits(&mut logits, seq.len())?;
+        seq.sampler().sample(&logits)
     }
-    fn tokenizer(&self) -> Tokenizer {
+    fn tokenizer(&self) -> Arc<Tokenizer> {
         self.tokenizer.clone()
     }
-    fn eos_tok(&self) -> u32 {
-        self.tokenizer
-            .get_vocab(true)
-            .get("</s>")
-            .copied()
-            .expect("Unable to extract `</s>` EOS token.")
+    fn eos_toks(&self) -> &[u32] {
+        &self.eos_toks
+    }
+    fn generation_config(&self) -> &GenerationConfig {
+        &self.generation_config
     }
 }
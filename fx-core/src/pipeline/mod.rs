@@ -1,18 +1,25 @@
+mod generation_config;
 mod mistral;
+pub use generation_config::GenerationConfig;
 pub use mistral::{MistralLoader, MistralSpecificConfig};
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+    cell::RefCell,
+    path::PathBuf,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 use candle_core::{DType, Device, Tensor};
-use crate::{
-    models::Cache,
-    request::Sequence,
-};
+use tokenizers::Tokenizer;
+use crate::{request::Sequence, sampler::Logprobs};
 
 pub trait ModelPaths {
     fn get_weight_filenames(&self) -> &[PathBuf];
     fn get_config_filename(&self) -> &PathBuf;
     fn get_tokenizer_filename(&self) -> &PathBuf;
+    fn get_generation_config_filename(&self) -> Option<&PathBuf>;
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf>;
 }
 
 pub enum TokenSource {
@@ -24,6 +31,8 @@ pub enum TokenSource {
 pub struct SimpleModelPaths<P> {
     tokenizer_filename: P,
     config_filename: P,
+    generation_config_filename: Option<P>,
+    tokenizer_config_filename: Option<P>,
     filenames: Vec<P>,
 }
 
@@ -37,6 +46,12 @@ impl ModelPaths for SimpleModelPaths<PathBuf> {
     fn get_weight_filenames(&self) -> &[PathBuf] {
         &self.filenames
     }
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        self.generation_config_filename.as_ref()
+    }
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf> {
+        self.tokenizer_config_filename.as_ref()
+    }
 }
 
 pub trait Loader {
@@ -69,11 +84,17 @@ pub trait Loader {
 
 pub trait Pipeline: Send + Sync {
     fn forward(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>) -> Result<Tensor>;
+    /// Like [`Pipeline::forward`], but returns the logits of every new position of each sequence,
+    /// shaped `(context_len, vocab_size)`. Used to verify speculative tokens in one pass.
+    fn forward_all(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>) -> Result<Vec<Tensor>>;
     fn tokenize_prompt(&self, prompt: &str) -> Result<Vec<u32>>;
     fn device(&self) -> &Device;
     fn num_hidden_layers(&self) -> usize;
-    fn cache(&self) -> &Cache;
+    fn vocab_size(&self) -> usize;
     fn sample(&mut self, logits: Tensor, seq: Rc<RefCell<Sequence>>) -> Result<Logprobs>;
-    fn tokenizer(&self) -> Tokenizer;
-    fn eos_tok(&self) -> u32;
+    fn tokenizer(&self) -> Arc<Tokenizer>;
+    /// The tokens which end a completion. May be empty for unusual tokenizers.
+    fn eos_toks(&self) -> &[u32];
+    /// Defaults for the sampling parameters which a request leaves unset.
+    fn generation_config(&self) -> &GenerationConfig;
 }
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// Number of tokens before the first generated one which are decoded with it, so that it gets the
+/// leading space a SentencePiece decoder strips at the start of the text.
+const INITIAL_CONTEXT: usize = 5;
+
+/// Decodes the tokens of a sequence as they are generated. Each token is decoded together with the
+/// tokens before it, as decoding it alone loses its leading space, and text is only emitted once
+/// it ends with complete UTF-8 characters. Special tokens such as EOS add no text.
+#[derive(Clone, Debug)]
+pub struct IncrementalDetokenizer {
+    /// Start of the tokens which are decoded as context for the pending ones.
+    prefix_offset: usize,
+    /// Start of the tokens whose text has not been emitted yet.
+    read_offset: usize,
+}
+
+impl IncrementalDetokenizer {
+    /// A detokenizer which emits the text of the tokens after the first `start`.
+    pub fn new(start: usize) -> Self {
+        Self {
+            prefix_offset: start.saturating_sub(INITIAL_CONTEXT),
+            read_offset: start,
+        }
+    }
+
+    /// The text added by the tokens at the end of `tokens` whose text has not been emitted yet.
+    /// Empty if they end with an incomplete character, in which case their text is emitted
+    /// with that of the next tokens.
+    pub fn step(&mut self, tokens: &[u32], tokenizer: &Tokenizer) -> Result<String> {
+        let decode = |tokens: &[u32]| {
+            tokenizer
+                .decode(tokens, true)
+                .map_err(|e| anyhow::Error::msg(e.to_string()))
+        };
+        let prefix = decode(&tokens[self.prefix_offset..self.read_offset])?;
+        let text = decode(&tokens[self.prefix_offset..])?;
+        if text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        let Some(delta) = text.get(prefix.len()..).filter(|delta| !delta.is_empty()) else {
+            return Ok(String::new());
+        };
+        let delta = delta.to_string();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = tokens.len();
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{
+        decoders::{
+            byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence, strip::Strip,
+            DecoderWrapper,
+        },
+        models::wordlevel::WordLevel,
+        normalizers::replace::Replace,
+        AddedToken, Tokenizer,
+    };
+
+    use super::IncrementalDetokenizer;
+
+    const EOS: u32 = 0;
+    const A: u32 = 1;
+    const B: u32 = 2;
+    /// The two bytes of `é`.
+    const E_ACUTE: [u32; 2] = [3, 4];
+
+    /// A tokenizer which decodes like a SentencePiece one with byte fallback, such as Mistral's.
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            ("</s>", EOS),
+            ("\u{2581}a", A),
+            ("\u{2581}b", B),
+            ("<0xC3>", E_ACUTE[0]),
+            ("<0xA9>", E_ACUTE[1]),
+        ]
+        .into_iter()
+        .map(|(token, id)| (token.to_string(), id))
+        .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).build().unwrap());
+        tokenizer.with_decoder(DecoderWrapper::Sequence(Sequence::new(vec![
+            Replace::new("\u{2581}", " ").unwrap().into(),
+            ByteFallback::new().into(),
+            Fuse::new().into(),
+            Strip::new(' ', 1, 0).into(),
+        ])));
+        tokenizer.add_special_tokens(&[AddedToken::from("</s>", true)]);
+        tokenizer
+    }
+
+    /// The text emitted for each token after the first `start` of `tokens`.
+    fn steps(tokens: &[u32], start: usize) -> Vec<String> {
+        let tokenizer = tokenizer();
+        let mut detokenizer = IncrementalDetokenizer::new(start);
+        (start + 1..=tokens.len())
+            .map(|len| detokenizer.step(&tokens[..len], &tokenizer).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_leading_space_of_generated_tokens() {
+        assert_eq!(steps(&[A, B, A], 1), vec![" b", " a"]);
+    }
+
+    #[test]
+    fn strips_the_leading_space_of_the_first_token() {
+        assert_eq!(steps(&[A, B], 0), vec!["a", " b"]);
+    }
+
+    #[test]
+    fn emits_a_multibyte_character_once_it_is_complete() {
+        let tokens = [A, E_ACUTE[0], E_ACUTE[1], B];
+        assert_eq!(steps(&tokens, 1), vec!["", "é", " b"]);
+    }
+
+    #[test]
+    fn special_tokens_add_no_text() {
+        assert_eq!(steps(&[A, B, EOS], 1), vec![" b", ""]);
+    }
+}
@@ -1,41 +1,600 @@
 use crate::{
+    constraints::{Constraint, TokenConstraint},
+    deref_refcell,
+    logits_processors::{LogitsContext, LogitsProcessor},
     models::Cache,
-    response::Response
+    response::{Choice, Completion, CompletionChunk, FinishReason, Response, TokenLogprob, Usage},
+    sampler::{decode_token, Logprobs, Sampler},
+    stopping_criteria::{StoppingContext, StoppingCriterion},
 };
+use anyhow::Result;
+use candle_core::{Device, Tensor};
 use std::{
-    cell::Cell,
-    sync::mpsc::Sender,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{mpsc::Sender, Arc},
+    time::{Duration, Instant},
 };
+use thiserror::Error;
+use tokenizers::Tokenizer;
+
+use self::detokenizer::IncrementalDetokenizer;
+
+mod detokenizer;
+
+/// Name of the token ids tensor in a sequence snapshot.
+const SNAPSHOT_TOKENS: &str = "tokens";
+/// Mixed into the seed of a sequence to seed its copy for a draft model.
+const DRAFT_SEED_SALT: u64 = 0x9E37_79B9_7F4A_7C15;
+
+#[derive(Error, Debug)]
+enum SnapshotError {
+    #[error("Snapshot has no `{SNAPSHOT_TOKENS}` tensor.")]
+    MissingTokens,
+    #[error("Snapshot caches {cached} positions for {tokens} tokens.")]
+    LengthMismatch { cached: usize, tokens: usize },
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SamplingParamsError {
+    #[error("`temperature` must be non-negative, got {0}.")]
+    Temperature(f64),
+    #[error("`top_k` must be at least 1.")]
+    TopK,
+    #[error("`top_p` must be in (0, 1], got {0}.")]
+    TopP(f64),
+    #[error("`min_p` must be in [0, 1], got {0}.")]
+    MinP(f64),
+    #[error("`typical_p` must be in (0, 1], got {0}.")]
+    TypicalP(f64),
+    #[error("`top_a` must be in [0, 1], got {0}.")]
+    TopA(f64),
+    #[error("Mirostat `tau` and `eta` must be positive, got {tau} and {eta}.")]
+    Mirostat { tau: f32, eta: f32 },
+    #[error("`max_tokens` must be at least 1.")]
+    MaxTokens,
+    #[error("`min_tokens` ({min_tokens}) must be at most `max_tokens` ({max_tokens}).")]
+    MinTokens {
+        min_tokens: usize,
+        max_tokens: usize,
+    },
+    #[error("`ignore_eos` requires `max_tokens`.")]
+    IgnoreEos,
+    #[error("`repeat_penalty` must be positive, got {0}.")]
+    RepeatPenalty(f32),
+    #[error("Stop strings must not be empty.")]
+    EmptyStopString,
+    #[error("`n` must be at least 1.")]
+    N,
+    #[error("`best_of` must be at least `n` ({0}).")]
+    BestOf(usize),
+    #[error("Beam search returns `num_returned` beams and does not support `n` or `best_of`.")]
+    BeamSearchChoices,
+    #[error("`beam_width` must be at least 1.")]
+    BeamWidth,
+    #[error("`num_returned` must be between 1 and `beam_width` ({0}).")]
+    NumReturnedBeams(usize),
+    #[error("`length_penalty` must be finite, got {0}.")]
+    LengthPenalty(f32),
+    #[error("Beam search does not support constraints.")]
+    BeamSearchConstraint,
+    #[error("Logit bias of token {token} must be in [-100, 100], got {bias}.")]
+    LogitBias { token: u32, bias: f32 },
+    #[error("Logit bias token {token} is not in the vocab of {vocab_size} tokens.")]
+    LogitBiasToken { token: u32, vocab_size: usize },
+    #[error("`frequency_penalty` must be in [-2, 2], got {0}.")]
+    FrequencyPenalty(f32),
+    #[error("`presence_penalty` must be in [-2, 2], got {0}.")]
+    PresencePenalty(f32),
+}
+
+/// Parameters of Mirostat v2 sampling, which truncates the distribution to keep the surprise
+/// (negative log2 probability) of the sampled tokens close to `tau`.
+#[derive(Clone, Debug)]
+pub struct MirostatParams {
+    /// Target surprise, in bits.
+    pub tau: f32,
+    /// Learning rate of the truncation threshold.
+    pub eta: f32,
+}
+
+impl Default for MirostatParams {
+    fn default() -> Self {
+        Self { tau: 5., eta: 0.1 }
+    }
+}
+
+/// Parameters of beam search decoding.
+#[derive(Clone, Debug)]
+pub struct BeamSearchParams {
+    /// Number of hypotheses kept at every step.
+    pub beam_width: usize,
+    /// Beams are ranked by their cumulative logprob divided by `length^length_penalty`. Values
+    /// above 0 favor longer completions, values below 0 shorter ones.
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` beams have finished, instead of when no running beam can beat
+    /// the finished ones anymore.
+    pub early_stopping: bool,
+    /// Number of finished beams to return, most likely first.
+    pub num_returned: usize,
+}
+
+impl Default for BeamSearchParams {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.,
+            early_stopping: false,
+            num_returned: 1,
+        }
+    }
+}
+
+/// How to sample the completion of a [`Request`]. Unset fields take the defaults of the model's
+/// [`GenerationConfig`](crate::GenerationConfig) if it has one, and otherwise disable the
+/// corresponding behavior. The truncation methods (`top_k`, `top_p`, `min_p`, `top_a`,
+/// `typical_p`) may be combined and are applied in that order, after the temperature.
+#[derive(Clone, Debug, Default)]
+pub struct SamplingParams {
+    /// Divide the logits by this before sampling. `0` selects the most likely token (greedy),
+    /// unset is equivalent to `1`.
+    pub temperature: Option<f64>,
+    /// Only sample from the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Only sample from the smallest set of most likely tokens whose probabilities add up to
+    /// `top_p`.
+    pub top_p: Option<f64>,
+    /// Only sample from tokens with at least `min_p` times the probability of the most likely one.
+    pub min_p: Option<f64>,
+    /// Only sample from tokens with at least `top_a` times the squared probability of the most
+    /// likely one. At most 1, so that the most likely token is always kept.
+    pub top_a: Option<f64>,
+    /// Locally typical sampling: only sample from the smallest set of tokens whose probabilities
+    /// add up to `typical_p`, taking the tokens whose surprise is closest to the entropy of the
+    /// distribution first.
+    pub typical_p: Option<f64>,
+    /// Sample with Mirostat v2 instead of the truncation methods. Its threshold is adapted after
+    /// every token of the sequence.
+    pub mirostat: Option<MirostatParams>,
+    /// Maximum number of tokens to generate.
+    pub max_tokens: Option<usize>,
+    /// Do not generate EOS or a stop token, and do not stop at a stop string or regex, until this
+    /// many tokens were generated.
+    pub min_tokens: usize,
+    /// Keep generating after EOS, until `max_tokens` or another stop condition.
+    pub ignore_eos: bool,
+    /// Penalty applied to the logits of tokens which occur in the last `repeat_last_n` tokens.
+    pub repeat_penalty: Option<f32>,
+    /// Window for `repeat_penalty`. Unset means the whole sequence, including the prompt.
+    pub repeat_last_n: Option<usize>,
+    /// Subtracted from the logit of a token once per time it has been generated.
+    pub frequency_penalty: Option<f32>,
+    /// Subtracted from the logit of a token if it has been generated at least once.
+    pub presence_penalty: Option<f32>,
+    /// Stop generating once one of these tokens is sampled.
+    pub stop_toks: Option<Vec<u32>>,
+    /// Stop generating once the completion contains one of these strings. The completion is
+    /// trimmed before the earliest match.
+    pub stop_strings: Option<Vec<String>>,
+    /// Stop generating once the completion matches one of these regexes anywhere. The completion
+    /// is not trimmed.
+    pub stop_regexes: Option<Vec<String>>,
+    /// Stop generating once this much time has passed since the request was received. Checked
+    /// after every generated token.
+    pub timeout: Option<Duration>,
+    /// Number of most likely alternatives to report for each sampled token.
+    pub top_n_logprobs: usize,
+    /// Report the log probability of each generated token, with `top_n_logprobs` alternatives.
+    pub logprobs: bool,
+    /// Report the ids of the generated tokens, including the one which ended the completion even
+    /// if its text was trimmed.
+    pub return_token_ids: bool,
+    /// Number of completions to return. Unset means 1.
+    pub n: Option<usize>,
+    /// Generate `best_of` completions and return the `n` with the highest cumulative logprob.
+    /// Unset means `n`.
+    pub best_of: Option<usize>,
+    /// Decode with beam search instead of sampling. The logits processors and penalties still
+    /// apply, but the other sampling options are ignored as beam search is deterministic.
+    pub beam_search: Option<BeamSearchParams>,
+    /// Seed of the random number generator used for sampling. Each sequence has its own generator,
+    /// so the same seed, prompt and parameters produce the same output whatever else is being
+    /// generated at the same time. Unset picks a random seed.
+    pub seed: Option<u64>,
+    /// Only generate text matching this regex, JSON schema or grammar.
+    pub constraint: Option<Constraint>,
+    /// Added to the logits of the given tokens before sampling. Biases are in [-100, 100], and a
+    /// bias of -100 bans the token.
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// Custom logits processors, run in order after the penalties and `logit_bias` and before the
+    /// constraint. See [`LogitsProcessor`].
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    /// Custom stopping criteria, checked in order after the built-in ones. See
+    /// [`StoppingCriterion`].
+    pub stopping_criteria: Vec<Arc<dyn StoppingCriterion>>,
+}
+
+impl SamplingParams {
+    /// Validate the parameters for a model with `vocab_size` tokens.
+    pub fn validate(&self, vocab_size: usize) -> Result<(), SamplingParamsError> {
+        if let Some(temperature) = self.temperature {
+            if temperature.is_nan() || temperature < 0. {
+                return Err(SamplingParamsError::Temperature(temperature));
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err(SamplingParamsError::TopK);
+        }
+        if let Some(top_p) = self.top_p {
+            if top_p.is_nan() || top_p <= 0. || top_p > 1. {
+                return Err(SamplingParamsError::TopP(top_p));
+            }
+        }
+        if let Some(min_p) = self.min_p {
+            if !(0. ..=1.).contains(&min_p) {
+                return Err(SamplingParamsError::MinP(min_p));
+            }
+        }
+        if let Some(top_a) = self.top_a {
+            if !(0. ..=1.).contains(&top_a) {
+                return Err(SamplingParamsError::TopA(top_a));
+            }
+        }
+        if let Some(typical_p) = self.typical_p {
+            if typical_p.is_nan() || typical_p <= 0. || typical_p > 1. {
+                return Err(SamplingParamsError::TypicalP(typical_p));
+            }
+        }
+        if let Some(MirostatParams { tau, eta }) = self.mirostat {
+            let is_valid = |x: f32| x.is_finite() && x > 0.;
+            if !is_valid(tau) || !is_valid(eta) {
+                return Err(SamplingParamsError::Mirostat { tau, eta });
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(SamplingParamsError::MaxTokens);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            if self.min_tokens > max_tokens {
+                return Err(SamplingParamsError::MinTokens {
+                    min_tokens: self.min_tokens,
+                    max_tokens,
+                });
+            }
+        }
+        if self.ignore_eos && self.max_tokens.is_none() {
+            return Err(SamplingParamsError::IgnoreEos);
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            if repeat_penalty.is_nan() || repeat_penalty <= 0. {
+                return Err(SamplingParamsError::RepeatPenalty(repeat_penalty));
+            }
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            if !(-2. ..=2.).contains(&frequency_penalty) {
+                return Err(SamplingParamsError::FrequencyPenalty(frequency_penalty));
+            }
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            if !(-2. ..=2.).contains(&presence_penalty) {
+                return Err(SamplingParamsError::PresencePenalty(presence_penalty));
+            }
+        }
+        if self
+            .stop_strings
+            .as_ref()
+            .is_some_and(|stops| stops.iter().any(String::is_empty))
+        {
+            return Err(SamplingParamsError::EmptyStopString);
+        }
+        for (token, bias) in self.logit_bias.iter().flatten() {
+            if !(-100. ..=100.).contains(bias) {
+                return Err(SamplingParamsError::LogitBias {
+                    token: *token,
+                    bias: *bias,
+                });
+            }
+            if *token as usize >= vocab_size {
+                return Err(SamplingParamsError::LogitBiasToken {
+                    token: *token,
+                    vocab_size,
+                });
+            }
+        }
+        if self.n == Some(0) {
+            return Err(SamplingParamsError::N);
+        }
+        if self.best_of.is_some_and(|best_of| best_of < self.n()) {
+            return Err(SamplingParamsError::BestOf(self.n()));
+        }
+        if let Some(beam_search) = &self.beam_search {
+            if self.n.is_some() || self.best_of.is_some() {
+                return Err(SamplingParamsError::BeamSearchChoices);
+            }
+            if beam_search.beam_width == 0 {
+                return Err(SamplingParamsError::BeamWidth);
+            }
+            if !(1..=beam_search.beam_width).contains(&beam_search.num_returned) {
+                return Err(SamplingParamsError::NumReturnedBeams(
+                    beam_search.beam_width,
+                ));
+            }
+            if !beam_search.length_penalty.is_finite() {
+                return Err(SamplingParamsError::LengthPenalty(
+                    beam_search.length_penalty,
+                ));
+            }
+            if self.constraint.is_some() {
+                return Err(SamplingParamsError::BeamSearchConstraint);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn n(&self) -> usize {
+        self.n.unwrap_or(1)
+    }
+
+    pub fn best_of(&self) -> usize {
+        self.best_of.unwrap_or(self.n())
+    }
+}
+
+#[derive(Error, Debug)]
+enum PromptError {
+    #[error("The prompt must have at least one token.")]
+    Empty,
+    #[error("Prompt token {token} is not in the vocab of {vocab_size} tokens.")]
+    Token { token: u32, vocab_size: usize },
+}
 
 pub struct Request {
+    /// Ignored if `prompt_token_ids` is set.
     pub prompt: String,
+    /// The already tokenized prompt, used instead of tokenizing `prompt`.
+    pub prompt_token_ids: Option<Vec<u32>>,
+    pub sampling_params: SamplingParams,
     pub response: Sender<Response>,
+    /// Send a [`Response::Chunk`] for every generated token instead of a single
+    /// [`Response::Done`].
+    pub is_streaming: bool,
+    /// Resume from a snapshot saved with `save_snapshot` instead of prefilling a prompt. The tokens
+    /// of the snapshot are the prompt, so `prompt` and `prompt_token_ids` are ignored.
+    pub restore_snapshot: Option<PathBuf>,
+    /// Save the tokens and KV cache of the sequence to this safetensors file once it is done. Not
+    /// supported with beam search or `best_of` greater than 1.
+    pub save_snapshot: Option<PathBuf>,
+}
+
+/// Check that a prompt is not empty and only has tokens of a vocab of `vocab_size` tokens.
+pub(crate) fn check_prompt_tokens(tokens: &[u32], vocab_size: usize) -> Result<()> {
+    if tokens.is_empty() {
+        return Err(PromptError::Empty.into());
+    }
+    if let Some(token) = tokens.iter().find(|token| **token as usize >= vocab_size) {
+        return Err(PromptError::Token {
+            token: *token,
+            vocab_size,
+        }
+        .into());
+    }
+    Ok(())
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopReason {
+    Eos,
+    StopTok(u32),
+    /// Index of the matched stop string in `stop_strings`.
+    StopString(usize),
+    /// Index of the matched regex in `stop_regexes`.
+    StopRegex(usize),
+    Length(usize),
+    /// The request was cancelled through a [`CancelFlag`](crate::CancelFlag).
+    Cancelled,
+    /// The `timeout` of the request passed.
+    Timeout,
+    /// Reported by a custom [`StoppingCriterion`].
+    Custom(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SequenceState {
-    Done,
+    Done(StopReason),
+    Error,
+    /// A sequence which was discarded: a beam search hypothesis, or a sequence whose request was
+    /// cancelled because its receiver went away.
+    Pruned,
     Running,
     Waiting,
 }
 
+/// The sequences generated for one request. Their choices are collected here and answered
+/// together, once all of them are done.
+pub struct SequenceGroup {
+    n: usize,
+    best_of: usize,
+    /// Finished choices with their cumulative logprob.
+    choices: Vec<(f32, Choice)>,
+    responder: Sender<Response>,
+    failed: bool,
+    /// Whether the receiver went away, so nothing more can be sent.
+    cancelled: bool,
+}
+
+impl SequenceGroup {
+    pub fn new(n: usize, best_of: usize, responder: Sender<Response>) -> Self {
+        Self {
+            n,
+            best_of,
+            choices: Vec::new(),
+            responder,
+            failed: false,
+            cancelled: false,
+        }
+    }
+
+    pub fn responder(&self) -> Sender<Response> {
+        self.responder.clone()
+    }
+
+    /// Whether the request failed. The other sequences of a failed request fail too.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Whether the receiver of the request went away. The sequences of a cancelled request are
+    /// pruned, as their output cannot be sent anywhere.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Send `response`, or cancel the request if its receiver went away.
+    fn send(&mut self, response: Response) {
+        if self.responder.send(response).is_err() {
+            self.cancelled = true;
+        }
+    }
+
+    pub fn send_chunk(&mut self, chunk: CompletionChunk) {
+        if !self.failed && !self.cancelled {
+            self.send(Response::Chunk(chunk));
+        }
+    }
+
+    /// Record a finished choice. Once all `best_of` choices are finished, the `n` with the highest
+    /// cumulative logprob are sent.
+    pub fn add_choice(&mut self, cumulative_logprob: f32, choice: Choice) {
+        if self.failed || self.cancelled {
+            return;
+        }
+        self.choices.push((cumulative_logprob, choice));
+        if self.choices.len() < self.best_of {
+            return;
+        }
+        let mut choices = std::mem::take(&mut self.choices);
+        let usage = Usage::combine(choices.iter().map(|(_, choice)| &choice.usage));
+        choices.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        choices.truncate(self.n);
+        choices.sort_by_key(|(_, choice)| choice.index);
+        let choices = choices
+            .into_iter()
+            .enumerate()
+            .map(|(index, (_, choice))| Choice { index, ..choice })
+            .collect();
+        self.send(Response::Done(Completion { choices, usage }));
+    }
+
+    /// Fail the request because of a choice which failed with `usage`. The other sequences of the
+    /// group are set to [`SequenceState::Error`] before they generate another token, see
+    /// [`Sequence::stop_with_group`]. Does nothing if the request already failed.
+    pub fn fail(&mut self, e: Box<dyn Error + Send + Sync>, usage: Usage) {
+        if !self.failed && !self.cancelled {
+            self.failed = true;
+            let usage = Usage::combine(
+                self.choices
+                    .iter()
+                    .map(|(_, choice)| &choice.usage)
+                    .chain([&usage]),
+            );
+            self.send(Response::Failed {
+                error: e,
+                finish_reason: FinishReason::Error,
+                usage,
+            });
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Sequence {
     tokens: Vec<u32>,
+    prompt_len: usize,
     id: usize,
     state: Cell<SequenceState>,
     gen_idx: usize,
     cache: Cache,
+    group: Rc<RefCell<SequenceGroup>>,
+    /// Index of this sequence's choice in the group.
+    index: usize,
+    sampler: Sampler,
+    constraint: Option<TokenConstraint>,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    stopping_criteria: Vec<Arc<dyn StoppingCriterion>>,
+    /// Stop strings are only looked for once this many tokens were generated.
+    min_tokens: usize,
+    /// Length of the completion before the text of the `min_tokens`-th token. Stop strings and
+    /// regexes are only looked for after it.
+    min_tokens_offset: Option<usize>,
+    stop_strings: Vec<String>,
+    is_streaming: bool,
+    detokenizer: IncrementalDetokenizer,
+    /// Decoded completion, trimmed before the matched stop string if there is one.
+    completion: String,
+    /// Number of bytes of `completion` which have been streamed.
+    streamed_len: usize,
+    /// Index of the stop string which was matched.
+    matched_stop: Option<usize>,
+    logprobs: Option<Vec<TokenLogprob>>,
+    return_token_ids: bool,
+    cumulative_logprob: f32,
+    /// When the request was received.
+    arrived: Instant,
+    prefill_started: Option<Instant>,
+    first_token_at: Option<Instant>,
+    /// Number of prompt tokens which were already in the KV cache when the prefill started.
+    cached_prompt_tokens: usize,
+    /// Where to save a snapshot of this sequence once it is done.
+    snapshot_path: Option<PathBuf>,
 }
 
 impl Sequence {
-    pub fn new_waiting(tokens: Vec<u32>, id: usize, layers: usize) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_waiting(
+        tokens: Vec<u32>,
+        id: usize,
+        layers: usize,
+        group: Rc<RefCell<SequenceGroup>>,
+        sampler: Sampler,
+        constraint: Option<TokenConstraint>,
+        logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+        stopping_criteria: Vec<Arc<dyn StoppingCriterion>>,
+        sampling_params: &SamplingParams,
+        is_streaming: bool,
+    ) -> Self {
+        let prompt_len = tokens.len();
         Self {
             tokens,
+            prompt_len,
             id,
             state: Cell::new(SequenceState::Waiting),
             gen_idx: 0,
             cache: Cache::new(layers),
+            group,
+            index: 0,
+            sampler,
+            constraint,
+            logits_processors,
+            stopping_criteria,
+            min_tokens: sampling_params.min_tokens,
+            min_tokens_offset: (sampling_params.min_tokens == 0).then_some(0),
+            stop_strings: sampling_params.stop_strings.clone().unwrap_or_default(),
+            is_streaming,
+            detokenizer: IncrementalDetokenizer::new(prompt_len),
+            completion: String::new(),
+            streamed_len: 0,
+            matched_stop: None,
+            logprobs: sampling_params.logprobs.then(Vec::new),
+            return_token_ids: sampling_params.return_token_ids,
+            cumulative_logprob: 0.,
+            arrived: Instant::now(),
+            prefill_started: None,
+            first_token_at: None,
+            cached_prompt_tokens: 0,
+            snapshot_path: None,
         }
     }
 
@@ -51,6 +610,20 @@ impl Sequence {
         self.state.get() == SequenceState::Running
     }
 
+    /// Stop this sequence if it is running but its request failed or was cancelled because of
+    /// another sequence of the group, such as a sibling choice or beam.
+    pub fn stop_with_group(&self) {
+        if !self.is_running() {
+            return;
+        }
+        let group = deref_refcell!(self.group);
+        if group.is_failed() {
+            self.set_state(SequenceState::Error);
+        } else if group.is_cancelled() {
+            self.set_state(SequenceState::Pruned);
+        }
+    }
+
     pub fn get_tokens(&self) -> &[u32] {
         &self.tokens
     }
@@ -59,7 +632,682 @@ impl Sequence {
         &mut self.gen_idx
     }
 
+    /// Whether the prompt has been run through the model.
+    pub fn is_prefilled(&self) -> bool {
+        self.gen_idx > 0
+    }
+
+    /// Record that the prompt is about to be run through the model, reusing what is already in the
+    /// KV cache. Does nothing if this was already recorded, such as for forked sequences.
+    pub fn start_prefill(&mut self) {
+        if self.prefill_started.is_some() {
+            return;
+        }
+        self.prefill_started = Some(Instant::now());
+        self.cached_prompt_tokens = self.cache.len().min(self.prompt_len);
+    }
+
+    /// The tokens and time spent on this sequence so far.
+    pub fn usage(&self) -> Usage {
+        let now = Instant::now();
+        let prefill_started = self.prefill_started.unwrap_or(now);
+        let first_token_at = self.first_token_at.unwrap_or(now);
+        Usage {
+            prompt_tokens: self.prompt_len,
+            completion_tokens: self.completion_tokens().len(),
+            cached_prompt_tokens: self.cached_prompt_tokens,
+            queue_time: prefill_started - self.arrived,
+            prefill_time: first_token_at.saturating_duration_since(prefill_started),
+            decode_time: now - first_token_at,
+        }
+    }
+
     pub fn cache(&self) -> &Cache {
         &self.cache
     }
+
+    pub fn responder(&self) -> Sender<Response> {
+        deref_refcell!(self.group).responder()
+    }
+
+    pub fn group(&self) -> &Rc<RefCell<SequenceGroup>> {
+        &self.group
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn sampler(&mut self) -> &mut Sampler {
+        &mut self.sampler
+    }
+
+    pub fn is_constrained(&self) -> bool {
+        self.constraint.is_some()
+    }
+
+    /// Run the logits processors of the request on the logits predicted after the first `len`
+    /// tokens, then mask the tokens the constraint does not allow. The constraint must be at that
+    /// position.
+    pub fn process_logits(&self, logits: &mut [f32], len: usize) -> Result<()> {
+        let ctx = LogitsContext::new(&self.tokens[..len], self.prompt_len);
+        for processor in &self.logits_processors {
+            processor.process(logits, &ctx)?;
+        }
+        if let Some(constraint) = &self.constraint {
+            let allowed = constraint.allowed_tokens();
+            for (tok, logit) in logits.iter_mut().enumerate() {
+                if !allowed.get(tok).copied().unwrap_or(false) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_state(&self, state: SequenceState) {
+        self.state.set(state);
+    }
+
+    /// Add a sampled token, decode the text it adds to the completion and look for stop strings in
+    /// it. Returns the logprobs of the token, if they were requested.
+    pub fn add_token(
+        &mut self,
+        logprobs: Logprobs,
+        tokenizer: &Tokenizer,
+    ) -> Result<Option<TokenLogprob>> {
+        if let Some(constraint) = &mut self.constraint {
+            constraint.advance(logprobs.token)?;
+        }
+        self.tokens.push(logprobs.token);
+        self.first_token_at.get_or_insert_with(Instant::now);
+        self.cumulative_logprob += logprobs.logprob;
+        let text_offset = self.completion.len();
+        let text = self.detokenizer.step(&self.tokens, tokenizer)?;
+        self.completion.push_str(&text);
+        if self.min_tokens_offset.is_none() && self.completion_tokens().len() >= self.min_tokens {
+            // The text of the `min_tokens`-th token may already contain a stop string.
+            self.min_tokens_offset = Some(text_offset);
+        }
+        let stop = self
+            .min_tokens_offset
+            .and_then(|min_tokens_offset| self.find_stop_string(text_offset, min_tokens_offset));
+        let text = match stop {
+            Some((idx, pos)) => {
+                self.matched_stop = Some(idx);
+                // Text which was already streamed cannot be taken back.
+                self.completion.truncate(pos.max(self.streamed_len));
+                self.completion
+                    .get(text_offset..)
+                    .unwrap_or_default()
+                    .to_string()
+            }
+            None => text,
+        };
+
+        let Some(all_logprobs) = &mut self.logprobs else {
+            return Ok(None);
+        };
+        let (_, bytes) = decode_token(tokenizer, logprobs.token)?;
+        let logprobs = TokenLogprob {
+            token: logprobs.token,
+            logprob: logprobs.logprob,
+            text: text.clone(),
+            bytes,
+            text_offset,
+            top_logprobs: logprobs.top_logprobs,
+        };
+        all_logprobs.push(logprobs.clone());
+        Ok(Some(logprobs))
+    }
+
+    /// The index and position of the earliest stop string in the completion which ends after
+    /// byte `new_text_offset` and starts at or after byte `min_start`.
+    fn find_stop_string(&self, new_text_offset: usize, min_start: usize) -> Option<(usize, usize)> {
+        let longest = self.stop_strings.iter().map(String::len).max()?;
+        let mut start = new_text_offset.saturating_sub(longest - 1);
+        while !self.completion.is_char_boundary(start) {
+            start -= 1;
+        }
+        let start = start.max(min_start);
+        self.stop_strings
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, stop)| {
+                let pos = self.completion[start..].find(stop.as_str())?;
+                Some((idx, start + pos))
+            })
+            .min_by_key(|(_, pos)| *pos)
+    }
+
+    /// The completion text which has not been streamed yet. Unless the sequence is `done`, text
+    /// which may be the start of a stop string is held back.
+    pub fn take_stream_text(&mut self, done: bool) -> String {
+        let end = if done {
+            self.completion.len()
+        } else {
+            self.completion.len() - self.stop_string_prefix_len()
+        };
+        let end = end.max(self.streamed_len);
+        let text = self.completion[self.streamed_len..end].to_string();
+        self.streamed_len = end;
+        text
+    }
+
+    /// Length of the longest end of the completion which is the start of a stop string.
+    fn stop_string_prefix_len(&self) -> usize {
+        self.stop_strings
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len()).rev().find(|len| {
+                    stop.is_char_boundary(*len) && self.completion.ends_with(&stop[..*len])
+                })
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.is_streaming
+    }
+
+    /// The decoded completion so far.
+    pub fn completion(&self) -> &str {
+        &self.completion
+    }
+
+    /// Sum of the logprobs of the generated tokens.
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    /// The logprobs of every generated token, if they were requested.
+    pub fn logprobs(&self) -> Option<&[TokenLogprob]> {
+        self.logprobs.as_deref()
+    }
+
+    /// The generated tokens, excluding the prompt.
+    pub fn completion_tokens(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// Whether the ids of the generated tokens are reported.
+    pub fn returns_token_ids(&self) -> bool {
+        self.return_token_ids
+    }
+
+    /// Check the stopping criteria of the request after a token was added. Returns the reason of
+    /// the first one which is met.
+    pub fn is_done(&self, eos_toks: &[u32]) -> Option<StopReason> {
+        let ctx = StoppingContext::new(
+            &self.tokens,
+            self.prompt_len,
+            &self.completion,
+            self.min_tokens_offset.unwrap_or(self.completion.len()),
+            self.matched_stop,
+            self.arrived.elapsed(),
+            eos_toks,
+        );
+        self.stopping_criteria
+            .iter()
+            .find_map(|criterion| criterion.should_stop(&ctx))
+    }
+
+    /// Save the token ids and the KV cache of this sequence to a safetensors file, so that it can be
+    /// resumed later with [`Sequence::restore_snapshot`] without prefilling again.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        // Only every token but the last one is cached, but speculative decoding can leave the KV
+        // of rejected proposals after them.
+        self.cache.truncate(self.tokens.len().saturating_sub(1));
+        let mut tensors = self.cache.tensors()?;
+        tensors.insert(
+            SNAPSHOT_TOKENS.to_string(),
+            Tensor::new(self.tokens.as_slice(), &Device::Cpu)?,
+        );
+        candle_core::safetensors::save(&tensors, path)?;
+        Ok(())
+    }
+
+    pub(crate) fn set_snapshot_path(&mut self, path: Option<PathBuf>) {
+        self.snapshot_path = path;
+    }
+
+    /// Where to save a snapshot of this sequence once it is done, if anywhere.
+    pub(crate) fn snapshot_path(&self) -> Option<&Path> {
+        self.snapshot_path.as_deref()
+    }
+
+    /// Replace the tokens and KV cache of this sequence by those saved with
+    /// [`Sequence::save_snapshot`], loading the cache onto `device`.
+    pub fn restore_snapshot<P: AsRef<Path>>(&mut self, path: P, device: &Device) -> Result<()> {
+        let tensors = candle_core::safetensors::load(path, device)?;
+        let tokens = tensors
+            .get(SNAPSHOT_TOKENS)
+            .ok_or(SnapshotError::MissingTokens)?
+            .to_vec1::<u32>()?;
+        let layers = self.cache.lock().len();
+        let cache = Cache::from_tensors(&tensors, layers)?;
+
+        // Every token but the last one, which has not been fed through the model yet, is cached.
+        let cached = cache
+            .lock()
+            .iter()
+            .map(|layer| layer.len())
+            .max()
+            .unwrap_or(0);
+        let prefilled = cached > 0;
+        if cache
+            .lock()
+            .iter()
+            .any(|layer| layer.len() != cached || (prefilled && cached + 1 != tokens.len()))
+        {
+            Err(SnapshotError::LengthMismatch {
+                cached,
+                tokens: tokens.len(),
+            })?;
+        }
+
+        // The restored tokens are the prompt of whatever is generated next.
+        self.prompt_len = tokens.len();
+        self.detokenizer = IncrementalDetokenizer::new(tokens.len());
+        self.completion.clear();
+        self.min_tokens_offset = (self.min_tokens == 0).then_some(0);
+        self.streamed_len = 0;
+        self.matched_stop = None;
+        if let Some(logprobs) = &mut self.logprobs {
+            logprobs.clear();
+        }
+        self.cumulative_logprob = 0.;
+        self.tokens = tokens;
+        self.cache = cache;
+        self.gen_idx = usize::from(prefilled);
+        Ok(())
+    }
+
+    /// Fork this sequence into a new one with id `id`. This is meant to be called after prefill:
+    /// the fork shares the cached prompt KV with this sequence, so both can continue generating
+    /// independently without prefilling the prompt again.
+    pub fn fork(&self, id: usize) -> Result<Self> {
+        Ok(Self {
+            id,
+            cache: self.cache.fork()?,
+            ..self.clone()
+        })
+    }
+
+    /// Copy this sequence for a draft model with `layers` layers. The copy has an empty KV cache of
+    /// its own, and a sampler whose random numbers are independent from this sequence's ones, as
+    /// the verification of its proposals must not correlate with how they were drawn.
+    pub fn fork_draft(&self, layers: usize) -> Self {
+        Self {
+            cache: Cache::new(layers),
+            sampler: self.sampler.derive(DRAFT_SEED_SALT),
+            ..self.clone()
+        }
+    }
+
+    /// Append a token without decoding it or checking whether the sequence is done, such as a
+    /// speculative token which may be rolled back with [`Sequence::truncate_tokens`].
+    pub fn push_token(&mut self, tok: u32) {
+        self.tokens.push(tok);
+    }
+
+    /// Discard all but the first `len` tokens. Only tokens added by [`Sequence::push_token`]
+    /// should be discarded, as the decoded completion is not updated.
+    pub fn truncate_tokens(&mut self, len: usize) {
+        self.tokens.truncate(len);
+    }
+
+    /// Fork this sequence after prefill into choice `index` of the same request, sampled by
+    /// `sampler`.
+    pub fn fork_choice(&self, id: usize, index: usize, sampler: Sampler) -> Result<Self> {
+        Ok(Self {
+            index,
+            sampler,
+            cached_prompt_tokens: self.prompt_len,
+            ..self.fork(id)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        rc::Rc,
+        sync::{mpsc::channel, Arc},
+    };
+
+    use tokenizers::{
+        decoders::{
+            byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence as Decoders, strip::Strip,
+            DecoderWrapper,
+        },
+        models::wordlevel::WordLevel,
+        normalizers::replace::Replace,
+        AddedToken, Tokenizer,
+    };
+
+    use super::{
+        BeamSearchParams, MirostatParams, SamplingParams, SamplingParamsError, Sequence,
+        SequenceGroup, StopReason,
+    };
+    use crate::{
+        sampler::{Logprobs, Sampler},
+        stopping_criteria::chain,
+    };
+
+    const VOCAB_SIZE: usize = 8;
+
+    const EOS: u32 = 0;
+    const HELLO: u32 = 1;
+    const WOR: u32 = 2;
+    const LD: u32 = 3;
+    const BANG: u32 = 4;
+
+    /// A tokenizer which decodes like a SentencePiece one, such as Mistral's.
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            ("</s>", EOS),
+            ("\u{2581}Hello", HELLO),
+            ("\u{2581}wor", WOR),
+            ("ld", LD),
+            ("!", BANG),
+        ]
+        .into_iter()
+        .map(|(token, id)| (token.to_string(), id))
+        .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).build().unwrap());
+        tokenizer.with_decoder(DecoderWrapper::Sequence(Decoders::new(vec![
+            Replace::new("\u{2581}", " ").unwrap().into(),
+            ByteFallback::new().into(),
+            Fuse::new().into(),
+            Strip::new(' ', 1, 0).into(),
+        ])));
+        tokenizer.add_special_tokens(&[AddedToken::from("</s>", true)]);
+        tokenizer
+    }
+
+    /// A streaming sequence whose prompt is `Hello`.
+    fn sequence(params: &SamplingParams) -> Sequence {
+        let (responder, _) = channel();
+        Sequence::new_waiting(
+            vec![HELLO],
+            0,
+            0,
+            Rc::new(RefCell::new(SequenceGroup::new(1, 1, responder))),
+            Sampler::new(0, params, Arc::new(tokenizer())),
+            None,
+            Vec::new(),
+            chain(params).unwrap(),
+            params,
+            true,
+        )
+    }
+
+    fn add_token(seq: &mut Sequence, token: u32) {
+        let logprobs = Logprobs {
+            token,
+            logprob: 0.,
+            top_logprobs: Vec::new(),
+        };
+        seq.add_token(logprobs, &tokenizer()).unwrap();
+    }
+
+    fn stop_strings(stop_strings: &[&str]) -> SamplingParams {
+        SamplingParams {
+            stop_strings: Some(stop_strings.iter().map(|stop| stop.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn top_a_must_keep_the_most_likely_token() {
+        let params = |top_a| SamplingParams {
+            top_a: Some(top_a),
+            ..Default::default()
+        };
+        assert_eq!(params(0.).validate(VOCAB_SIZE), Ok(()));
+        assert_eq!(params(1.).validate(VOCAB_SIZE), Ok(()));
+        assert_eq!(
+            params(1.5).validate(VOCAB_SIZE),
+            Err(SamplingParamsError::TopA(1.5))
+        );
+        assert!(params(f64::NAN).validate(VOCAB_SIZE).is_err());
+    }
+
+    fn validate(params: SamplingParams) -> Result<(), SamplingParamsError> {
+        params.validate(VOCAB_SIZE)
+    }
+
+    #[test]
+    fn default_params_are_valid() {
+        assert_eq!(validate(SamplingParams::default()), Ok(()));
+    }
+
+    #[test]
+    fn invalid_sampling_options_are_rejected() {
+        assert_eq!(
+            validate(SamplingParams {
+                temperature: Some(-1.),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::Temperature(-1.))
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                top_k: Some(0),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::TopK)
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                top_p: Some(0.),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::TopP(0.))
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                min_p: Some(1.5),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::MinP(1.5))
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                typical_p: Some(0.),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::TypicalP(0.))
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                mirostat: Some(MirostatParams { tau: 0., eta: 0.1 }),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::Mirostat { tau: 0., eta: 0.1 })
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                repeat_penalty: Some(0.),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::RepeatPenalty(0.))
+        );
+    }
+
+    #[test]
+    fn invalid_lengths_are_rejected() {
+        assert_eq!(
+            validate(SamplingParams {
+                max_tokens: Some(0),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::MaxTokens)
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                min_tokens: 3,
+                max_tokens: Some(2),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::MinTokens {
+                min_tokens: 3,
+                max_tokens: 2
+            })
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                ignore_eos: true,
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::IgnoreEos)
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                ignore_eos: true,
+                max_tokens: Some(2),
+                ..Default::default()
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            validate(stop_strings(&["a", ""])),
+            Err(SamplingParamsError::EmptyStopString)
+        );
+    }
+
+    #[test]
+    fn invalid_choices_are_rejected() {
+        assert_eq!(
+            validate(SamplingParams {
+                n: Some(0),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::N)
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                n: Some(2),
+                best_of: Some(1),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::BestOf(2))
+        );
+        let beam_search = |beam_width, num_returned| SamplingParams {
+            beam_search: Some(BeamSearchParams {
+                beam_width,
+                num_returned,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            validate(SamplingParams {
+                n: Some(2),
+                ..beam_search(2, 2)
+            }),
+            Err(SamplingParamsError::BeamSearchChoices)
+        );
+        assert_eq!(
+            validate(beam_search(0, 1)),
+            Err(SamplingParamsError::BeamWidth)
+        );
+        assert_eq!(
+            validate(beam_search(2, 3)),
+            Err(SamplingParamsError::NumReturnedBeams(2))
+        );
+        assert_eq!(validate(beam_search(2, 2)), Ok(()));
+    }
+
+    #[test]
+    fn invalid_logit_bias_is_rejected() {
+        let logit_bias = |token, bias| SamplingParams {
+            logit_bias: Some(HashMap::from([(token, bias)])),
+            ..Default::default()
+        };
+        assert_eq!(validate(logit_bias(1, -100.)), Ok(()));
+        assert_eq!(
+            validate(logit_bias(1, 101.)),
+            Err(SamplingParamsError::LogitBias {
+                token: 1,
+                bias: 101.
+            })
+        );
+        assert_eq!(
+            validate(logit_bias(VOCAB_SIZE as u32, 1.)),
+            Err(SamplingParamsError::LogitBiasToken {
+                token: VOCAB_SIZE as u32,
+                vocab_size: VOCAB_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn penalties_out_of_range_are_rejected() {
+        assert_eq!(
+            validate(SamplingParams {
+                frequency_penalty: Some(2.5),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::FrequencyPenalty(2.5))
+        );
+        assert_eq!(
+            validate(SamplingParams {
+                presence_penalty: Some(-2.5),
+                ..Default::default()
+            }),
+            Err(SamplingParamsError::PresencePenalty(-2.5))
+        );
+    }
+
+    #[test]
+    fn stop_string_split_across_tokens_is_trimmed() {
+        let mut seq = sequence(&stop_strings(&["world"]));
+        add_token(&mut seq, WOR);
+        assert_eq!(seq.is_done(&[EOS]), None);
+        // `wor` may be the start of the stop string, so it is held back.
+        assert_eq!(seq.take_stream_text(false), " ");
+        add_token(&mut seq, LD);
+        assert_eq!(seq.is_done(&[EOS]), Some(StopReason::StopString(0)));
+        assert_eq!(seq.take_stream_text(true), "");
+        assert_eq!(seq.completion(), " ");
+    }
+
+    #[test]
+    fn held_back_prefix_of_a_stop_string_is_streamed_once_it_is_not_one() {
+        let mut seq = sequence(&stop_strings(&["world"]));
+        add_token(&mut seq, WOR);
+        assert_eq!(seq.take_stream_text(false), " ");
+        add_token(&mut seq, BANG);
+        assert_eq!(seq.is_done(&[EOS]), None);
+        assert_eq!(seq.take_stream_text(false), "wor!");
+        assert_eq!(seq.completion(), " wor!");
+    }
+
+    #[test]
+    fn earliest_stop_string_wins() {
+        let mut seq = sequence(&stop_strings(&["or", "wor"]));
+        add_token(&mut seq, WOR);
+        assert_eq!(seq.is_done(&[EOS]), Some(StopReason::StopString(1)));
+        assert_eq!(seq.completion(), " ");
+    }
+
+    #[test]
+    fn stop_strings_wait_for_min_tokens() {
+        let params = SamplingParams {
+            min_tokens: 2,
+            ..stop_strings(&["wor"])
+        };
+        let mut seq = sequence(&params);
+        add_token(&mut seq, WOR);
+        assert_eq!(seq.is_done(&[EOS]), None);
+        // The text of the `min_tokens`-th token is searched, but not the text before it.
+        add_token(&mut seq, WOR);
+        assert_eq!(seq.is_done(&[EOS]), Some(StopReason::StopString(0)));
+        assert_eq!(seq.completion(), " wor ");
+    }
 }
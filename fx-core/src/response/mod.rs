@@ -1,5 +1,164 @@
-use std::error::Error;
+use std::{error::Error, time::Duration};
+
+use crate::request::StopReason;
+
+/// Why the generation of a choice ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FinishReason {
+    /// A stop token, stop string, stop regex or custom stopping criterion was matched.
+    Stop,
+    /// The model generated its EOS token.
+    Eos,
+    /// `max_tokens` were generated.
+    Length,
+    Cancelled,
+    Timeout,
+    /// The request failed while generating, as reported by [`Response::Failed`] and by the last
+    /// chunk of the streaming choice which failed.
+    Error,
+}
+
+impl From<StopReason> for FinishReason {
+    fn from(stop_reason: StopReason) -> Self {
+        match stop_reason {
+            StopReason::Eos => Self::Eos,
+            StopReason::Length(_) => Self::Length,
+            StopReason::Cancelled => Self::Cancelled,
+            StopReason::Timeout => Self::Timeout,
+            StopReason::StopTok(_)
+            | StopReason::StopString(_)
+            | StopReason::StopRegex(_)
+            | StopReason::Custom(_) => Self::Stop,
+        }
+    }
+}
+
+/// The tokens processed for a choice or request and the time spent on them.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Prompt tokens whose KV cache was reused instead of computed, such as those of a restored
+    /// snapshot or of a choice forked off another one.
+    pub cached_prompt_tokens: usize,
+    /// Time between receiving the request and starting the prefill.
+    pub queue_time: Duration,
+    /// Time between starting the prefill and the first generated token.
+    pub prefill_time: Duration,
+    /// Time between the first and the last generated token.
+    pub decode_time: Duration,
+}
+
+impl Usage {
+    /// The usage of a request from that of its choices, which share the prompt and are generated
+    /// concurrently: completion tokens are summed and the timings are those of the slowest choice.
+    pub(crate) fn combine<'a>(usages: impl IntoIterator<Item = &'a Usage>) -> Self {
+        let mut total = Usage::default();
+        for (i, usage) in usages.into_iter().enumerate() {
+            total.prompt_tokens = usage.prompt_tokens;
+            total.completion_tokens += usage.completion_tokens;
+            // The other choices are forked off the first one once its prompt is computed.
+            if i == 0 || usage.cached_prompt_tokens < total.cached_prompt_tokens {
+                total.cached_prompt_tokens = usage.cached_prompt_tokens;
+            }
+            total.queue_time = total.queue_time.max(usage.queue_time);
+            total.prefill_time = total.prefill_time.max(usage.prefill_time);
+            total.decode_time = total.decode_time.max(usage.decode_time);
+        }
+        total
+    }
+}
+
+/// One of the most likely tokens at a sampling step.
+#[derive(Clone, Debug)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    pub text: String,
+    /// UTF-8 bytes of the token. A token may encode only part of a multi-byte character, in which
+    /// case `text` cannot represent it.
+    pub bytes: Vec<u8>,
+}
+
+/// Log probability of a generated token and of the most likely alternatives.
+#[derive(Clone, Debug)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    /// Text this token added to the completion.
+    pub text: String,
+    pub bytes: Vec<u8>,
+    /// Byte offset of `text` in the completion.
+    pub text_offset: usize,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// The text generated in one step of a streaming request. The last chunk of each choice has a
+/// `finish_reason` and the `usage` of the choice, and a `stop_reason` unless the choice failed.
+#[derive(Clone, Debug)]
+pub struct CompletionChunk {
+    /// Index of the choice this chunk belongs to.
+    pub index: usize,
+    pub text: String,
+    pub logprobs: Option<TokenLogprob>,
+    /// The generated token, if `return_token_ids` was set.
+    pub token_ids: Option<Vec<u32>>,
+    pub stop_reason: Option<StopReason>,
+    pub finish_reason: Option<FinishReason>,
+    pub usage: Option<Usage>,
+}
+
+/// One of the completions of a request.
+#[derive(Clone, Debug)]
+pub struct Choice {
+    pub index: usize,
+    pub text: String,
+    pub logprobs: Option<Vec<TokenLogprob>>,
+    /// The generated tokens, if `return_token_ids` was set.
+    pub token_ids: Option<Vec<u32>>,
+    pub stop_reason: StopReason,
+    pub finish_reason: FinishReason,
+    pub usage: Usage,
+}
+
+/// The full output of a non-streaming request.
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub choices: Vec<Choice>,
+    /// Usage of the whole request, including the choices which were generated for `best_of` but
+    /// not returned.
+    pub usage: Usage,
+}
+
+/// A finished hypothesis of a beam search request.
+#[derive(Clone, Debug)]
+pub struct Beam {
+    pub text: String,
+    /// Cumulative logprob, normalized by the length penalty.
+    pub score: f32,
+    /// The generated tokens, if `return_token_ids` was set.
+    pub token_ids: Option<Vec<u32>>,
+    pub stop_reason: StopReason,
+    pub finish_reason: FinishReason,
+    /// Usage of the beam's own sequence. Its completion tokens are those of the beam.
+    pub usage: Usage,
+}
 
 pub enum Response {
+    /// The request was rejected before generating, such as because of invalid sampling params.
     Error(Box<dyn Error + Send + Sync>),
-}
\ No newline at end of file
+    /// The request failed while generating. `finish_reason` is always [`FinishReason::Error`], and
+    /// `usage` covers the choices which finished and the one which failed.
+    Failed {
+        error: Box<dyn Error + Send + Sync>,
+        finish_reason: FinishReason,
+        usage: Usage,
+    },
+    Chunk(CompletionChunk),
+    Done(Completion),
+    /// The best beams of a beam search request, best first, and the usage of the whole search.
+    Beams {
+        beams: Vec<Beam>,
+        usage: Usage,
+    },
+}
@@ -0,0 +1,433 @@
+use std::{cmp::Ordering, collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use tokenizers::Tokenizer;
+
+use crate::{
+    request::{MirostatParams, SamplingParams},
+    response::TopLogprob,
+};
+
+/// The sampled token, its log probability and the `top_n_logprobs` most likely alternatives.
+#[derive(Clone, Debug)]
+pub struct Logprobs {
+    pub token: u32,
+    pub logprob: f32,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// Samples the next token of a sequence from the logits.
+#[derive(Clone)]
+pub struct Sampler {
+    temperature: Option<f64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    min_p: Option<f64>,
+    top_a: Option<f64>,
+    typical_p: Option<f64>,
+    mirostat: Option<MirostatParams>,
+    /// Mirostat's current maximum surprise.
+    mirostat_mu: f32,
+    top_n_logprobs: usize,
+    tokenizer: Arc<Tokenizer>,
+    seed: u64,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, params: &SamplingParams, tokenizer: Arc<Tokenizer>) -> Self {
+        Self {
+            temperature: params.temperature,
+            top_k: params.top_k,
+            top_p: params.top_p,
+            min_p: params.min_p,
+            top_a: params.top_a,
+            typical_p: params.typical_p,
+            mirostat: params.mirostat.clone(),
+            mirostat_mu: params
+                .mirostat
+                .as_ref()
+                .map_or(0., |mirostat| 2. * mirostat.tau),
+            top_n_logprobs: params.top_n_logprobs,
+            tokenizer,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// A copy of this sampler with its own random numbers, seeded with this sampler's seed mixed
+    /// with `salt` so that it stays reproducible.
+    pub(crate) fn derive(&self, salt: u64) -> Self {
+        let seed = self.seed ^ salt;
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            ..self.clone()
+        }
+    }
+
+    /// Sample from `logits`, which has a single element per vocab entry and has already been run
+    /// through the sequence's logits processors.
+    pub fn sample(&mut self, logits: &[f32]) -> Result<Logprobs> {
+        let logprobs = self.logprobs(logits);
+        let token = if self.is_greedy() {
+            argmax(&logprobs)
+        } else {
+            let distribution = self.distribution(&logprobs);
+            self.pick(&distribution)?
+        };
+        self.output(&logprobs, token)
+    }
+
+    fn is_greedy(&self) -> bool {
+        self.temperature
+            .is_some_and(|temperature| temperature <= 0.)
+    }
+
+    /// The log probability of every token after the temperature.
+    pub(crate) fn logprobs(&self, logits: &[f32]) -> Vec<f32> {
+        if self.is_greedy() {
+            return log_softmax(logits);
+        }
+        let temperature = self.temperature.unwrap_or(1.) as f32;
+        let logits = logits
+            .iter()
+            .map(|logit| logit / temperature)
+            .collect::<Vec<_>>();
+        log_softmax(&logits)
+    }
+
+    /// The sampled token with its logprob and the most likely alternatives.
+    pub(crate) fn output(&self, logprobs: &[f32], token: u32) -> Result<Logprobs> {
+        Ok(Logprobs {
+            token,
+            logprob: logprobs[token as usize],
+            top_logprobs: self.top_logprobs(logprobs)?,
+        })
+    }
+
+    /// The distribution tokens are actually sampled from, as `(token, prob)` pairs, most likely
+    /// first: `logprobs` truncated by Mirostat or by top-k, top-p, min-p, top-a and typical
+    /// sampling (in that order) and renormalized, or only the most likely token when sampling
+    /// greedily.
+    pub(crate) fn distribution(&self, logprobs: &[f32]) -> Vec<(u32, f32)> {
+        if self.is_greedy() {
+            return vec![(argmax(logprobs), 1.)];
+        }
+        let mut candidates = sorted_desc(logprobs)
+            .into_iter()
+            .map(|(tok, logprob)| (tok, logprob.exp()))
+            .collect::<Vec<_>>();
+        if self.mirostat.is_some() {
+            let keep = candidates
+                .iter()
+                .take_while(|(_, prob)| -prob.log2() <= self.mirostat_mu)
+                .count();
+            candidates.truncate(keep.max(1));
+        } else {
+            self.truncate(&mut candidates);
+        }
+        candidates.retain(|(_, prob)| *prob > 0.);
+
+        let total = candidates.iter().map(|(_, prob)| prob).sum::<f32>();
+        candidates.iter_mut().for_each(|(_, prob)| *prob /= total);
+        candidates
+    }
+
+    /// Apply the truncation methods to `(token, prob)` pairs, most likely first.
+    fn truncate(&self, candidates: &mut Vec<(u32, f32)>) {
+        if let Some(top_k) = self.top_k {
+            candidates.truncate(top_k);
+        }
+        if let Some(top_p) = self.top_p {
+            let mut cumulative = 0.;
+            let keep = candidates
+                .iter()
+                .take_while(|(_, prob)| {
+                    let below = cumulative < top_p;
+                    cumulative += f64::from(*prob);
+                    below
+                })
+                .count();
+            candidates.truncate(keep.max(1));
+        }
+        if let Some(min_p) = self.min_p {
+            let threshold = candidates[0].1 * min_p as f32;
+            candidates.retain(|(_, prob)| *prob >= threshold);
+        }
+        if let Some(top_a) = self.top_a {
+            let threshold = candidates[0].1.powi(2) * top_a as f32;
+            candidates.retain(|(_, prob)| *prob >= threshold);
+        }
+        if let Some(typical_p) = self.typical_p {
+            let total = candidates.iter().map(|(_, prob)| prob).sum::<f32>();
+            let entropy = candidates
+                .iter()
+                .map(|(_, prob)| prob / total)
+                .filter(|prob| *prob > 0.)
+                .map(|prob| -prob * prob.ln())
+                .sum::<f32>();
+            let mut by_typicality = candidates
+                .iter()
+                .map(|(tok, prob)| (*tok, prob / total, (-(prob / total).ln() - entropy).abs()))
+                .collect::<Vec<_>>();
+            by_typicality.sort_by(|a, b| a.2.total_cmp(&b.2));
+            let mut cumulative = 0.;
+            let keep = by_typicality
+                .iter()
+                .take_while(|(_, prob, _)| {
+                    let below = cumulative < typical_p;
+                    cumulative += f64::from(*prob);
+                    below
+                })
+                .map(|(tok, _, _)| *tok)
+                .collect::<HashSet<_>>();
+            candidates.retain(|(tok, _)| keep.contains(tok));
+        }
+    }
+
+    /// Sample a token from `(token, prob)` pairs.
+    pub(crate) fn pick(&mut self, distribution: &[(u32, f32)]) -> Result<u32> {
+        let distr = WeightedIndex::new(distribution.iter().map(|(_, prob)| *prob))?;
+        let tok = distribution[distr.sample(&mut self.rng)].0;
+        self.observe(distribution, tok);
+        Ok(tok)
+    }
+
+    /// Update the sampler's state after `tok` was generated from `distribution`, which only
+    /// matters for Mirostat.
+    pub(crate) fn observe(&mut self, distribution: &[(u32, f32)], tok: u32) {
+        let Some(mirostat) = &self.mirostat else {
+            return;
+        };
+        let prob = distribution
+            .iter()
+            .find(|(t, _)| *t == tok)
+            .map_or(0., |(_, prob)| *prob);
+        let surprise = -prob.log2();
+        if surprise.is_finite() {
+            self.mirostat_mu -= mirostat.eta * (surprise - mirostat.tau);
+        }
+    }
+
+    /// A uniformly random number in `[0, 1)`.
+    pub(crate) fn random(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn top_logprobs(&self, logprobs: &[f32]) -> Result<Vec<TopLogprob>> {
+        if self.top_n_logprobs == 0 {
+            return Ok(Vec::new());
+        }
+        let mut top = sorted_desc(logprobs);
+        top.truncate(self.top_n_logprobs);
+        top.into_iter()
+            .map(|(token, logprob)| {
+                let (text, bytes) = decode_token(&self.tokenizer, token)?;
+                Ok(TopLogprob {
+                    token,
+                    logprob,
+                    text,
+                    bytes,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The text and UTF-8 bytes of a single token.
+pub(crate) fn decode_token(tokenizer: &Tokenizer, token: u32) -> Result<(String, Vec<u8>)> {
+    // Byte fallback tokens such as `<0x0A>` stand for one raw byte, which may be part of a
+    // multi-byte character.
+    let byte = tokenizer.id_to_token(token).and_then(|piece| {
+        let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+        u8::from_str_radix(hex, 16).ok()
+    });
+    if let Some(byte) = byte {
+        return Ok((
+            String::from_utf8(vec![byte]).unwrap_or_default(),
+            vec![byte],
+        ));
+    }
+    let text = tokenizer
+        .decode(&[token], false)
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    let bytes = text.as_bytes().to_vec();
+    Ok((text, bytes))
+}
+
+pub(crate) fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum_exp = logits
+        .iter()
+        .map(|logit| (logit - max).exp())
+        .sum::<f32>()
+        .ln()
+        + max;
+    logits.iter().map(|logit| logit - log_sum_exp).collect()
+}
+
+fn argmax(logprobs: &[f32]) -> u32 {
+    logprobs
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(tok, _)| tok as u32)
+        .unwrap_or_default()
+}
+
+/// All `(token, logprob)` pairs, most likely first.
+pub(crate) fn sorted_desc(logprobs: &[f32]) -> Vec<(u32, f32)> {
+    let mut sorted = logprobs
+        .iter()
+        .enumerate()
+        .map(|(tok, logprob)| (tok as u32, *logprob))
+        .collect::<Vec<_>>();
+    sorted.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use super::Sampler;
+    use crate::request::{MirostatParams, SamplingParams};
+
+    /// Every token is about as likely, so that sampling depends on the random numbers.
+    const LOGITS: [f32; 8] = [1., 1.1, 0.9, 1., 1.2, 0.8, 1., 1.05];
+
+    fn sampler(seed: u64, params: &SamplingParams) -> Sampler {
+        let tokenizer = Tokenizer::new(WordLevel::builder().build().unwrap());
+        Sampler::new(seed, params, Arc::new(tokenizer))
+    }
+
+    /// Probabilities of the tokens, most likely first.
+    const PROBS: [f32; 5] = [0.5, 0.2, 0.15, 0.1, 0.05];
+
+    /// The tokens of the distribution sampled from given `PROBS`.
+    fn distribution(sampler: &Sampler) -> Vec<u32> {
+        let logprobs = PROBS.map(f32::ln);
+        sampler
+            .distribution(&logprobs)
+            .into_iter()
+            .map(|(tok, _)| tok)
+            .collect()
+    }
+
+    fn truncated(params: SamplingParams) -> Vec<u32> {
+        distribution(&sampler(0, &params))
+    }
+
+    fn sample_n(sampler: &mut Sampler, n: usize) -> Vec<u32> {
+        (0..n)
+            .map(|_| sampler.sample(&LOGITS).unwrap().token)
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_samples_the_same_tokens() {
+        let params = SamplingParams {
+            temperature: Some(1.),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let tokens = sample_n(&mut sampler(42, &params), 32);
+        assert_eq!(sample_n(&mut sampler(42, &params), 32), tokens);
+        assert_ne!(sample_n(&mut sampler(43, &params), 32), tokens);
+    }
+
+    #[test]
+    fn derived_samplers_are_reproducible() {
+        let params = SamplingParams::default();
+        let derived = sample_n(&mut sampler(42, &params).derive(7), 32);
+        assert_eq!(sample_n(&mut sampler(42, &params).derive(7), 32), derived);
+        assert_ne!(sample_n(&mut sampler(42, &params), 32), derived);
+    }
+
+    #[test]
+    fn top_k_and_top_p_keep_the_most_likely_tokens() {
+        let top_k = SamplingParams {
+            top_k: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(truncated(top_k), vec![0, 1, 2]);
+        // Tokens are kept until their cumulative probability reaches `top_p`.
+        let top_p = SamplingParams {
+            top_p: Some(0.6),
+            ..Default::default()
+        };
+        assert_eq!(truncated(top_p), vec![0, 1]);
+    }
+
+    #[test]
+    fn min_p_is_relative_to_the_most_likely_token() {
+        let params = SamplingParams {
+            min_p: Some(0.35),
+            ..Default::default()
+        };
+        assert_eq!(truncated(params), vec![0, 1]);
+    }
+
+    #[test]
+    fn top_a_is_relative_to_the_squared_most_likely_probability() {
+        let params = SamplingParams {
+            top_a: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(truncated(params), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn typical_keeps_the_tokens_closest_to_the_entropy() {
+        let params = SamplingParams {
+            typical_p: Some(0.3),
+            ..Default::default()
+        };
+        // The most likely token is far less surprising than the average token.
+        assert_eq!(truncated(params), vec![1, 2]);
+    }
+
+    #[test]
+    fn truncation_methods_apply_in_order() {
+        // Typical sampling only sees the tokens top-k kept, which makes the most likely one
+        // typical.
+        let params = SamplingParams {
+            top_k: Some(2),
+            typical_p: Some(0.3),
+            ..Default::default()
+        };
+        assert_eq!(truncated(params), vec![0]);
+    }
+
+    #[test]
+    fn mirostat_adapts_its_threshold_to_the_sampled_tokens() {
+        let params = SamplingParams {
+            mirostat: Some(MirostatParams { tau: 1.5, eta: 0.5 }),
+            ..Default::default()
+        };
+        let mut sampler = sampler(0, &params);
+        // Tokens with a surprise of at most `2 * tau` bits are kept at first.
+        assert_eq!(distribution(&sampler), vec![0, 1, 2]);
+        // Sampling a more surprising token than `tau` lowers the threshold.
+        let sampled_from = sampler.distribution(&PROBS.map(f32::ln));
+        sampler.observe(&sampled_from, 2);
+        assert_eq!(distribution(&sampler), vec![0, 1]);
+    }
+
+    #[test]
+    fn mirostat_keeps_the_most_likely_token() {
+        let params = SamplingParams {
+            mirostat: Some(MirostatParams { tau: 0.1, eta: 0.1 }),
+            ..Default::default()
+        };
+        assert_eq!(truncated(params), vec![0]);
+    }
+}
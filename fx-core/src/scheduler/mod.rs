@@ -1,87 +1,99 @@
-use std::{
-    collections::{ vec_deque::Iter, VecDeque },
-    iter::zip,
-};
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
-use crate::request::Sequence;
+use crate::{
+    deref_refcell,
+    request::{Sequence, SequenceState},
+};
 
 pub trait FcfsBacker {
     fn new() -> Self;
-    fn next(&mut self) -> Option<Sequence>;
-    fn add(&mut self, item: Sequence);
-    fn iter(&self) -> impl Iterator<Item = &Sequence>;
+    fn next(&mut self) -> Option<Rc<RefCell<Sequence>>>;
+    fn add(&mut self, item: Rc<RefCell<Sequence>>);
+    fn is_empty(&self) -> bool;
 }
 
-impl FcfsBacker for VecDeque<Sequence> {
+impl FcfsBacker for VecDeque<Rc<RefCell<Sequence>>> {
     fn new() -> Self {
         Self::new()
     }
-    fn add(&mut self, item: Sequence) {
+    fn add(&mut self, item: Rc<RefCell<Sequence>>) {
         self.push_back(item)
     }
-    fn next(&mut self) -> Option<Sequence> {
+    fn next(&mut self) -> Option<Rc<RefCell<Sequence>>> {
         self.pop_front()
     }
-    fn iter(&self) -> Iter<'_, Sequence> {
-        self.iter()
+    fn is_empty(&self) -> bool {
+        self.is_empty()
     }
 }
 
 pub struct SchedulerOutput {
-    pub seqs: Vec<Sequence>,
+    pub seqs: Box<[Rc<RefCell<Sequence>>]>,
 }
 
 pub struct Scheduler<Backer: FcfsBacker> {
     waiting: Backer,
-    running: Vec<Sequence>,
+    running: Vec<Rc<RefCell<Sequence>>>,
+    max_running: usize,
 }
 
 impl<Backer: FcfsBacker> Scheduler<Backer> {
-    pub fn new() -> Self {
+    /// `max_running` is the maximum number of sequences processed in one step.
+    pub fn new(max_running: usize) -> Self {
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
+            max_running,
         }
     }
 
-    pub fn add_seq(&mut self, seq: Sequence) {
+    pub fn add_seq(&mut self, seq: Rc<RefCell<Sequence>>) {
         self.waiting.add(seq)
     }
 
+    /// Add a sequence which must run alongside the currently running ones, such as a forked beam.
+    /// This bypasses the limit on running sequences.
+    pub fn add_running(&mut self, seq: Rc<RefCell<Sequence>>) {
+        deref_refcell!(seq).set_state(SequenceState::Running);
+        self.running.push(seq);
+    }
+
+    /// Whether there are no sequences left to process.
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty()
+            && self
+                .running
+                .iter()
+                .all(|seq| !deref_refcell!(seq).is_running())
+    }
+
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self) -> SchedulerOutput {
-        // Filter out all done sequences
-        let running = self.running.clone();
-        let mut running = running
-            .iter()
-            .filter(|seq| seq.is_running())
-            .cloned()
-            .collect::<Vec<_>>();
+        // Filter out all done sequences, and those of failed or cancelled requests
+        self.running.retain(|seq| {
+            let seq = deref_refcell!(seq);
+            seq.stop_with_group();
+            seq.is_running()
+        });
 
-        // If the waiting sequence will fit, add it. Keep track of its id.
-        let mut waiting_to_remove = Vec::new();
-        for seq in self.waiting.iter() {
-            if self.sequence_fits(&running, seq) {
-                waiting_to_remove.push(seq.id());
-                running.push(seq.clone());
-            }
-        }
-
-        // Remove sequences moved from waiting -> running.
+        // Move waiting sequences to running, first come first served, while they fit.
         let mut waiting = Backer::new();
-        for (id, seq) in zip(waiting_to_remove, self.waiting.iter()) {
-            if seq.id() != id {
-                waiting.add(seq.clone());
+        while let Some(seq) = self.waiting.next() {
+            if self.sequence_fits(&seq) {
+                deref_refcell!(seq).set_state(SequenceState::Running);
+                self.running.push(seq);
+            } else {
+                waiting.add(seq);
             }
         }
-
         self.waiting = waiting;
-        self.running = running.clone();
 
-        SchedulerOutput { seqs: running }
+        SchedulerOutput {
+            seqs: self.running.clone().into(),
+        }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], seq: &Sequence) -> bool {
-        todo!()
+    fn sequence_fits(&self, _seq: &Rc<RefCell<Sequence>>) -> bool {
+        self.running.len() < self.max_running
     }
 }
@@ -0,0 +1,400 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use regex_automata::meta::Regex;
+use thiserror::Error;
+
+use crate::request::{SamplingParams, StopReason};
+
+#[derive(Error, Debug)]
+enum StoppingCriteriaError {
+    #[error("Invalid stop regex `{pattern}`: {msg}")]
+    Regex { pattern: String, msg: String },
+}
+
+/// The state of the sequence whose completion is being checked, after a token was added.
+pub struct StoppingContext<'a> {
+    tokens: &'a [u32],
+    prompt_len: usize,
+    completion: &'a str,
+    min_tokens_offset: usize,
+    matched_stop_string: Option<usize>,
+    elapsed: Duration,
+    eos_toks: &'a [u32],
+}
+
+impl<'a> StoppingContext<'a> {
+    pub(crate) fn new(
+        tokens: &'a [u32],
+        prompt_len: usize,
+        completion: &'a str,
+        min_tokens_offset: usize,
+        matched_stop_string: Option<usize>,
+        elapsed: Duration,
+        eos_toks: &'a [u32],
+    ) -> Self {
+        Self {
+            tokens,
+            prompt_len,
+            completion,
+            min_tokens_offset,
+            matched_stop_string,
+            elapsed,
+            eos_toks,
+        }
+    }
+
+    /// All tokens so far, including the prompt.
+    pub fn tokens(&self) -> &[u32] {
+        self.tokens
+    }
+
+    /// The generated tokens, excluding the prompt.
+    pub fn completion_tokens(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// The token which was just added.
+    pub fn last_token(&self) -> Option<u32> {
+        self.completion_tokens().last().copied()
+    }
+
+    /// The decoded completion, trimmed before the matched stop string if there is one.
+    pub fn completion(&self) -> &str {
+        self.completion
+    }
+
+    /// The decoded completion from the text of the `min_tokens`-th token on, which is empty
+    /// until then.
+    pub fn completion_after_min_tokens(&self) -> &str {
+        self.completion
+            .get(self.min_tokens_offset..)
+            .unwrap_or_default()
+    }
+
+    /// Index of the stop string of the request which was found in the completion.
+    pub fn matched_stop_string(&self) -> Option<usize> {
+        self.matched_stop_string
+    }
+
+    /// Time since the request was received.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The model's EOS tokens.
+    pub fn eos_toks(&self) -> &[u32] {
+        self.eos_toks
+    }
+}
+
+/// Decides when a sequence is done.
+///
+/// Each request checks an ordered chain of criteria after every token: those built from its
+/// [`SamplingParams`] (EOS unless `ignore_eos`, `stop_toks`, `stop_strings`, `stop_regexes`, which
+/// all wait for `min_tokens`, then `max_tokens` and `timeout`), then
+/// [`SamplingParams::stopping_criteria`]. The first one which returns a [`StopReason`] ends
+/// the sequence with that reason. Criteria are shared by all sequences of the request, so any
+/// state which depends on the sequence must be derived from the [`StoppingContext`].
+pub trait StoppingCriterion: Send + Sync {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason>;
+}
+
+impl<F> StoppingCriterion for F
+where
+    F: Fn(&StoppingContext) -> Option<StopReason> + Send + Sync,
+{
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        self(ctx)
+    }
+}
+
+impl fmt::Debug for dyn StoppingCriterion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StoppingCriterion")
+    }
+}
+
+/// Stop once one of the model's EOS tokens is generated.
+#[derive(Clone, Debug)]
+pub struct EosToken;
+
+impl StoppingCriterion for EosToken {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        let tok = ctx.last_token()?;
+        ctx.eos_toks().contains(&tok).then_some(StopReason::Eos)
+    }
+}
+
+/// Stop once one of these tokens is generated.
+#[derive(Clone, Debug)]
+pub struct StopTokens(pub Vec<u32>);
+
+impl StoppingCriterion for StopTokens {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        let tok = ctx.last_token()?;
+        self.0.contains(&tok).then_some(StopReason::StopTok(tok))
+    }
+}
+
+/// Stop once a stop string of the request was found. The sequence looks for them itself, as it
+/// trims the completion before the match.
+#[derive(Clone, Debug)]
+struct StopStrings;
+
+impl StoppingCriterion for StopStrings {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        ctx.matched_stop_string().map(StopReason::StopString)
+    }
+}
+
+/// Stop once the decoded completion matches a regex, ignoring the text generated before the
+/// `min_tokens`-th token. Reports the index of the regex in `stop_regexes`.
+#[derive(Clone, Debug)]
+pub struct StopRegex {
+    regex: Regex,
+    index: usize,
+}
+
+impl StopRegex {
+    pub fn new(pattern: &str, index: usize) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|e| StoppingCriteriaError::Regex {
+            pattern: pattern.to_string(),
+            msg: e.to_string(),
+        })?;
+        Ok(Self { regex, index })
+    }
+}
+
+impl StoppingCriterion for StopRegex {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        self.regex
+            .is_match(ctx.completion_after_min_tokens())
+            .then_some(StopReason::StopRegex(self.index))
+    }
+}
+
+/// Stop once this many tokens were generated.
+#[derive(Clone, Debug)]
+pub struct MaxTokens(pub usize);
+
+impl StoppingCriterion for MaxTokens {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        (ctx.completion_tokens().len() >= self.0).then_some(StopReason::Length(self.0))
+    }
+}
+
+/// Stop once this much time has passed since the request was received.
+#[derive(Clone, Debug)]
+pub struct Timeout(pub Duration);
+
+impl StoppingCriterion for Timeout {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        (ctx.elapsed() >= self.0).then_some(StopReason::Timeout)
+    }
+}
+
+/// Stop once the flag is set, such as when the client of a request goes away. The completion
+/// generated so far is still returned.
+#[derive(Clone, Debug, Default)]
+pub struct CancelFlag(pub Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl StoppingCriterion for CancelFlag {
+    fn should_stop(&self, _ctx: &StoppingContext) -> Option<StopReason> {
+        self.0
+            .load(Ordering::Relaxed)
+            .then_some(StopReason::Cancelled)
+    }
+}
+
+/// Only check `criterion` once `min_tokens` tokens were generated.
+#[derive(Debug)]
+struct AfterMinTokens {
+    min_tokens: usize,
+    criterion: Arc<dyn StoppingCriterion>,
+}
+
+impl StoppingCriterion for AfterMinTokens {
+    fn should_stop(&self, ctx: &StoppingContext) -> Option<StopReason> {
+        if ctx.completion_tokens().len() < self.min_tokens {
+            return None;
+        }
+        self.criterion.should_stop(ctx)
+    }
+}
+
+/// The criteria of a request, in the order they are checked.
+pub(crate) fn chain(params: &SamplingParams) -> Result<Vec<Arc<dyn StoppingCriterion>>> {
+    let mut stops: Vec<Arc<dyn StoppingCriterion>> = Vec::new();
+    if !params.ignore_eos {
+        stops.push(Arc::new(EosToken));
+    }
+    if let Some(stop_toks) = &params.stop_toks {
+        stops.push(Arc::new(StopTokens(stop_toks.clone())));
+    }
+    if params.stop_strings.is_some() {
+        stops.push(Arc::new(StopStrings));
+    }
+    for (index, pattern) in params.stop_regexes.iter().flatten().enumerate() {
+        stops.push(Arc::new(StopRegex::new(pattern, index)?));
+    }
+
+    let mut chain = if params.min_tokens > 0 {
+        stops
+            .into_iter()
+            .map(|criterion| -> Arc<dyn StoppingCriterion> {
+                Arc::new(AfterMinTokens {
+                    min_tokens: params.min_tokens,
+                    criterion,
+                })
+            })
+            .collect()
+    } else {
+        stops
+    };
+    if let Some(max_tokens) = params.max_tokens {
+        chain.push(Arc::new(MaxTokens(max_tokens)));
+    }
+    if let Some(timeout) = params.timeout {
+        chain.push(Arc::new(Timeout(timeout)));
+    }
+    chain.extend(params.stopping_criteria.iter().cloned());
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        chain, CancelFlag, EosToken, MaxTokens, StopRegex, StopTokens, StoppingContext,
+        StoppingCriterion, Timeout,
+    };
+    use crate::request::{SamplingParams, StopReason};
+
+    const EOS: u32 = 0;
+    const PROMPT: u32 = 1;
+
+    /// The context after generating the tokens after the first of `tokens`, which decode to
+    /// `completion`.
+    fn ctx<'a>(tokens: &'a [u32], completion: &'a str) -> StoppingContext<'a> {
+        StoppingContext::new(tokens, 1, completion, 0, None, Duration::ZERO, &[EOS])
+    }
+
+    #[test]
+    fn eos_token_stops() {
+        assert_eq!(EosToken.should_stop(&ctx(&[PROMPT, 2], "")), None);
+        assert_eq!(
+            EosToken.should_stop(&ctx(&[PROMPT, 2, EOS], "")),
+            Some(StopReason::Eos)
+        );
+        // EOS in the prompt does not count.
+        assert_eq!(EosToken.should_stop(&ctx(&[EOS], "")), None);
+    }
+
+    #[test]
+    fn stop_tokens_stop() {
+        let stop = StopTokens(vec![3, 4]);
+        assert_eq!(stop.should_stop(&ctx(&[PROMPT, 2], "")), None);
+        assert_eq!(
+            stop.should_stop(&ctx(&[PROMPT, 4], "")),
+            Some(StopReason::StopTok(4))
+        );
+    }
+
+    #[test]
+    fn stop_regex_matches_anywhere() {
+        let stop = StopRegex::new(r"\d{3}", 1).unwrap();
+        assert_eq!(stop.should_stop(&ctx(&[PROMPT, 2], "a 12 b")), None);
+        assert_eq!(
+            stop.should_stop(&ctx(&[PROMPT, 2], "a 123 b")),
+            Some(StopReason::StopRegex(1))
+        );
+        assert!(StopRegex::new("(", 0).is_err());
+    }
+
+    #[test]
+    fn max_tokens_counts_generated_tokens() {
+        assert_eq!(MaxTokens(2).should_stop(&ctx(&[PROMPT, 2], "")), None);
+        assert_eq!(
+            MaxTokens(2).should_stop(&ctx(&[PROMPT, 2, 3], "")),
+            Some(StopReason::Length(2))
+        );
+    }
+
+    #[test]
+    fn timeout_stops() {
+        let timeout = Timeout(Duration::from_secs(1));
+        assert_eq!(timeout.should_stop(&ctx(&[PROMPT, 2], "")), None);
+        let late =
+            StoppingContext::new(&[PROMPT, 2], 1, "", 0, None, Duration::from_secs(2), &[EOS]);
+        assert_eq!(timeout.should_stop(&late), Some(StopReason::Timeout));
+    }
+
+    #[test]
+    fn cancel_flag_stops_once_set() {
+        let flag = CancelFlag::default();
+        assert_eq!(flag.should_stop(&ctx(&[PROMPT, 2], "")), None);
+        flag.cancel();
+        assert_eq!(
+            flag.should_stop(&ctx(&[PROMPT, 2], "")),
+            Some(StopReason::Cancelled)
+        );
+    }
+
+    #[test]
+    fn stop_conditions_wait_for_min_tokens() {
+        let params = SamplingParams {
+            min_tokens: 2,
+            max_tokens: Some(2),
+            stop_toks: Some(vec![3]),
+            ..Default::default()
+        };
+        let stops = chain(&params).unwrap();
+        let should_stop = |tokens: &[u32]| {
+            stops
+                .iter()
+                .find_map(|criterion| criterion.should_stop(&ctx(tokens, "")))
+        };
+        assert_eq!(should_stop(&[PROMPT, EOS]), None);
+        assert_eq!(should_stop(&[PROMPT, 3]), None);
+        assert_eq!(should_stop(&[PROMPT, 2, EOS]), Some(StopReason::Eos));
+        assert_eq!(should_stop(&[PROMPT, 2, 3]), Some(StopReason::StopTok(3)));
+        assert_eq!(should_stop(&[PROMPT, 2, 2]), Some(StopReason::Length(2)));
+    }
+
+    #[test]
+    fn stop_regex_ignores_text_before_min_tokens() {
+        let stop = StopRegex::new("ab", 0).unwrap();
+        // The text of the `min_tokens`-th token starts at byte 3.
+        fn after_min_tokens(completion: &str) -> StoppingContext<'_> {
+            StoppingContext::new(
+                &[PROMPT, 2, 3],
+                1,
+                completion,
+                3,
+                None,
+                Duration::ZERO,
+                &[EOS],
+            )
+        }
+        assert_eq!(stop.should_stop(&after_min_tokens("ab cd")), None);
+        assert_eq!(
+            stop.should_stop(&after_min_tokens("cd ab")),
+            Some(StopReason::StopRegex(0))
+        );
+    }
+}
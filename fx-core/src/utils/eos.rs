@@ -0,0 +1,115 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+/// EOS token of SentencePiece tokenizers, used if no configuration file names one.
+const DEFAULT_EOS_TOKEN: &str = "</s>";
+
+/// The EOS token ids of a model: the `eos_token_id` of `config.json` and `generation_config.json`
+/// (a single id or a list) and the `eos_token` of `tokenizer_config.json` (a token or an added
+/// token object). Falls back to `</s>`, and is empty if the tokenizer does not have it either.
+pub(crate) fn get_eos_toks(
+    config: &Path,
+    generation_config: Option<&Path>,
+    tokenizer_config: Option<&Path>,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<u32>> {
+    let mut eos_toks = Vec::new();
+    for path in [Some(config), generation_config].into_iter().flatten() {
+        let ids = match read_json(path)?.get("eos_token_id") {
+            Some(Value::Array(ids)) => ids.iter().filter_map(Value::as_u64).collect(),
+            Some(id) => id.as_u64().into_iter().collect(),
+            None => Vec::new(),
+        };
+        eos_toks.extend(ids.into_iter().filter_map(|id| u32::try_from(id).ok()));
+    }
+    if let Some(path) = tokenizer_config {
+        let tokenizer_config = read_json(path)?;
+        let token = match tokenizer_config.get("eos_token") {
+            Some(Value::Object(token)) => token.get("content").and_then(Value::as_str),
+            Some(token) => token.as_str(),
+            None => None,
+        };
+        eos_toks.extend(token.and_then(|token| tokenizer.token_to_id(token)));
+    }
+    if eos_toks.is_empty() {
+        eos_toks.extend(tokenizer.token_to_id(DEFAULT_EOS_TOKEN));
+    }
+    eos_toks.sort_unstable();
+    eos_toks.dedup();
+    Ok(eos_toks)
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use super::get_eos_toks;
+
+    const EOS: u32 = 2;
+    const EOT: u32 = 5;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = HashMap::from([("</s>".to_string(), EOS), ("<|eot|>".to_string(), EOT)]);
+        Tokenizer::new(WordLevel::builder().vocab(vocab).build().unwrap())
+    }
+
+    /// Write `json` to a file unique to the calling test.
+    fn write_json(name: &str, json: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fx-core-{}-{name}.json", std::process::id()));
+        fs::write(&path, json).unwrap();
+        path
+    }
+
+    #[test]
+    fn scalar_eos_token_id() {
+        let config = write_json("eos-scalar", r#"{"eos_token_id": 7}"#);
+        assert_eq!(
+            get_eos_toks(&config, None, None, &tokenizer()).unwrap(),
+            vec![7]
+        );
+    }
+
+    #[test]
+    fn list_eos_token_id_is_merged_with_the_generation_config() {
+        let config = write_json("eos-list", r#"{"eos_token_id": [7, 3]}"#);
+        let generation_config = write_json("eos-list-generation", r#"{"eos_token_id": 7}"#);
+        assert_eq!(
+            get_eos_toks(&config, Some(&generation_config), None, &tokenizer()).unwrap(),
+            vec![3, 7]
+        );
+    }
+
+    #[test]
+    fn eos_token_of_the_tokenizer_config() {
+        let config = write_json("eos-tokenizer", "{}");
+        let as_string = write_json("eos-tokenizer-string", r#"{"eos_token": "<|eot|>"}"#);
+        let as_object = write_json(
+            "eos-tokenizer-object",
+            r#"{"eos_token": {"content": "<|eot|>", "special": true}}"#,
+        );
+        for tokenizer_config in [as_string, as_object] {
+            assert_eq!(
+                get_eos_toks(&config, None, Some(&tokenizer_config), &tokenizer()).unwrap(),
+                vec![EOT]
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_sentencepiece_eos_token() {
+        let config = write_json("eos-fallback", "{}");
+        assert_eq!(
+            get_eos_toks(&config, None, None, &tokenizer()).unwrap(),
+            vec![EOS]
+        );
+    }
+}
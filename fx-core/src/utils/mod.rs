@@ -1,4 +1,5 @@
 pub(crate) mod dtype;
+pub(crate) mod eos;
 pub(crate) mod tokens;
 pub(crate) mod varbuilder_utils;
 
@@ -11,4 +12,41 @@ macro_rules! get_mut_arcmutex {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[macro_export]
+macro_rules! deref_refcell {
+    ($thing:expr) => {
+        loop {
+            if let Ok(inner) = $thing.try_borrow() {
+                break inner;
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! deref_mut_refcell {
+    ($thing:expr) => {
+        loop {
+            if let Ok(inner) = $thing.try_borrow_mut() {
+                break inner;
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! handle_seq_error {
+    ($fallible:expr, $response:expr) => {
+        match $fallible {
+            Ok(v) => v,
+            Err(e) => {
+                use $crate::response::Response;
+                // NOTE Unwrap reasoning: The reciever should really be there, otherwise it is their fault.
+                $response.send(Response::Error(e.into())).unwrap();
+                return;
+            }
+        }
+    };
+}